@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::NodeConfig;
+use crate::storage::{ChainStorage, KvStorage, DB_FILE, META_BODY_FLOOR};
+use crate::{
+    load_chain, load_genesis, logger, migration, Block, BlockRejection, ExecError, Ledger, MethaloxChain,
+    Receipt, StateSnapshot,
+};
+
+const EXPORT_VERSION: u32 = 1;
+
+// Differing balances listed in a report before the rest are only counted
+const MAX_REPORTED_BALANCES: usize = 10;
+
+const CHAIN_USAGE: &str = "\
+Usage: methalox_end_game chain export [OPTIONS]
+       methalox_end_game chain import --input <FILE> [NODE OPTIONS]
+
+export writes the block history of a stopped node, with its receipts and the
+state it recorded at the tip, as JSON lines.
+
+import replays an export into a fresh data directory, validating and applying
+every block like a block from the network. It stops at the first block whose
+fees or state root disagree with the replay, and finally compares the
+recorded state with the replayed one.
+
+Export options:
+  --data-dir <DIR>    Data directory of a full or archive node (default: data)
+  --output <FILE>     Export file
+
+Import options:
+  --input <FILE>      Export file
+  Any node option (--data-dir, --genesis, --storage, --history, ...) applies
+  as when running the node. No validator keys are needed or read.";
+
+/// One line of an export file: a header, then every block after genesis in
+/// order, then the state the exporting node recorded.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Header(ExportHeader),
+    Block(ExportedBlock),
+    State(StateSnapshot),
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportHeader {
+    version: u32,
+    genesis_hash: String,
+    height: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedBlock {
+    block: Block,
+    /// Receipts the exporting node stored for the block's transactions.
+    receipts: Vec<Receipt>,
+}
+
+/// `chain export` and `chain import`.
+pub fn run_command(args: &[String]) -> Result<(), String> {
+    let Some((command, flags)) = args.split_first() else {
+        return Err(CHAIN_USAGE.to_string());
+    };
+    if flags.iter().any(|f| f == "-h" || f == "--help") {
+        println!("{}", CHAIN_USAGE);
+        return Ok(());
+    }
+    match command.as_str() {
+        "export" => export(flags),
+        "import" => import(flags),
+        other => Err(format!("Unknown chain command: {}\n\n{}", other, CHAIN_USAGE)),
+    }
+}
+
+fn export(flags: &[String]) -> Result<(), String> {
+    let mut data_dir = PathBuf::from(crate::config::DEFAULT_DATA_DIR);
+    let mut output = None;
+    let mut iter = flags.iter();
+    while let Some(flag) = iter.next() {
+        let value = iter.next().ok_or_else(|| format!("Missing value for {}\n\n{}", flag, CHAIN_USAGE))?;
+        match flag.as_str() {
+            "--data-dir" => data_dir = PathBuf::from(value),
+            "--output" => output = Some(PathBuf::from(value)),
+            other => return Err(format!("Unknown argument: {}\n\n{}", other, CHAIN_USAGE)),
+        }
+    }
+    let output = output.ok_or_else(|| format!("--output is required\n\n{}", CHAIN_USAGE))?;
+
+    let db_path = data_dir.join(DB_FILE);
+    if !db_path.exists() {
+        return Err(format!("No chain database at {}", db_path.display()));
+    }
+    let mut storage = KvStorage::open(&db_path)?;
+    migration::upgrade(&mut storage)?;
+    let height = storage.tip()?.ok_or("Chain database is empty")?;
+    let body_floor: u64 = match storage.meta(META_BODY_FLOOR)? {
+        Some(bytes) => bincode::deserialize(&bytes).map_err(|e| format!("Corrupt body floor record: {}", e))?,
+        None => 0,
+    };
+    if body_floor > 0 {
+        return Err(format!(
+            "Blocks below #{} were pruned or skipped by fast sync; only a full or archive node can export its history",
+            body_floor
+        ));
+    }
+    let genesis_hash = storage.header(0)?.ok_or("Stored chain has no genesis block")?.hash;
+
+    let file = File::create(&output).map_err(|e| format!("Cannot create {}: {}", output.display(), e))?;
+    let mut out = BufWriter::new(file);
+    write_record(&mut out, &output, &Record::Header(ExportHeader { version: EXPORT_VERSION, genesis_hash, height }))?;
+    for index in 1..=height {
+        let block = storage.block(index)?.ok_or_else(|| format!("Block #{} is missing from the database", index))?;
+        let mut receipts = Vec::new();
+        for tx in &block.transactions {
+            if let Some(receipt) = storage.receipt(&tx.hash())?.filter(|r| r.block_index == index) {
+                receipts.push(receipt);
+            }
+        }
+        write_record(&mut out, &output, &Record::Block(ExportedBlock { block, receipts }))?;
+    }
+    let ledger = Ledger::load(&storage)?;
+    write_record(&mut out, &output, &Record::State(ledger.snapshot()))?;
+    out.flush().map_err(|e| format!("Cannot write {}: {}", output.display(), e))?;
+
+    println!("Exported blocks #1..#{} and the state at #{} to {}", height, height, output.display());
+    Ok(())
+}
+
+fn write_record(out: &mut impl Write, path: &Path, record: &Record) -> Result<(), String> {
+    serde_json::to_writer(&mut *out, record)
+        .map_err(|e| e.to_string())
+        .and_then(|()| out.write_all(b"\n").map_err(|e| e.to_string()))
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+fn import(flags: &[String]) -> Result<(), String> {
+    let mut input = None;
+    let mut node_flags = Vec::new();
+    let mut iter = flags.iter();
+    while let Some(flag) = iter.next() {
+        if flag == "--input" {
+            let value = iter.next().ok_or_else(|| format!("Missing value for --input\n\n{}", CHAIN_USAGE))?;
+            input = Some(PathBuf::from(value));
+        } else {
+            node_flags.push(flag.clone());
+        }
+    }
+    let input = input.ok_or_else(|| format!("--input is required\n\n{}", CHAIN_USAGE))?;
+    let Some(config) = NodeConfig::from_args(&node_flags)? else {
+        return Ok(());
+    };
+    logger::init(config.log_level);
+    config.prepare_data_dir()?;
+    let genesis = load_genesis(&config)?;
+
+    let file = File::open(&input).map_err(|e| format!("Cannot open {}: {}", input.display(), e))?;
+    let mut records = BufReader::new(file).lines().enumerate().map(|(line, text)| {
+        let text = text.map_err(|e| format!("Cannot read {}: {}", input.display(), e))?;
+        serde_json::from_str::<Record>(&text).map_err(|e| format!("{} line {}: {}", input.display(), line + 1, e))
+    });
+
+    let header = match records.next().transpose()? {
+        Some(Record::Header(header)) => header,
+        _ => return Err(format!("{} does not start with an export header", input.display())),
+    };
+    if header.version != EXPORT_VERSION {
+        return Err(format!("Unsupported export version {}", header.version));
+    }
+    let genesis_hash = MethaloxChain::genesis_block(&genesis).hash;
+    if header.genesis_hash != genesis_hash {
+        return Err(format!(
+            "Export was made from genesis {} but the configured genesis is {}",
+            header.genesis_hash, genesis_hash
+        ));
+    }
+
+    let mut chain = load_chain(&config, None, &genesis)?;
+    if chain.height() != 0 {
+        return Err(format!(
+            "{} already holds a chain at block #{}; import into a fresh data directory",
+            config.data_dir.display(),
+            chain.height()
+        ));
+    }
+
+    let mut recorded = None;
+    for record in records {
+        match record? {
+            Record::Block(exported) if recorded.is_none() => replay_block(&mut chain, exported)?,
+            Record::State(state) if recorded.is_none() => recorded = Some(state),
+            _ => return Err(format!("{} has records out of order", input.display())),
+        }
+    }
+    let recorded = recorded.ok_or_else(|| format!("{} ends without the recorded state; it is incomplete", input.display()))?;
+    if chain.height() != header.height {
+        return Err(format!("Export ends at block #{} but its header says #{}", chain.height(), header.height));
+    }
+    compare_state(&mut chain.ledger, recorded, header.height)?;
+
+    println!(
+        "Replayed {} block(s) into {}: every block and the recorded state at #{} match the replay",
+        chain.height(),
+        config.data_dir.display(),
+        chain.height()
+    );
+    Ok(())
+}
+
+/// Applies one exported block the way a block from a peer is applied, after
+/// checking its recorded receipts against the fees the replay charges.
+fn replay_block(chain: &mut MethaloxChain, exported: ExportedBlock) -> Result<(), String> {
+    let ExportedBlock { block, receipts } = exported;
+    let index = block.index;
+    if index != chain.next_height() {
+        return Err(format!("Export skips from block #{} to #{}", chain.height(), index));
+    }
+
+    for receipt in &receipts {
+        let Some(tx) = block.transactions.get(receipt.tx_index) else {
+            return Err(format!(
+                "Divergence at block #{}: fees — a receipt points at transaction {}, which the block does not have",
+                index, receipt.tx_index
+            ));
+        };
+        let fee = Ledger::tx_fee(tx);
+        if receipt.fee != fee || receipt.asset != tx.asset {
+            return Err(format!(
+                "Divergence at block #{}: fees — transaction {} recorded a fee of {} {}, the replay charges {} {}",
+                index, receipt.tx_index, receipt.fee, receipt.asset, fee, tx.asset
+            ));
+        }
+    }
+
+    match chain.try_apply_block(block.clone()) {
+        Ok(()) => Ok(()),
+        Err(BlockRejection::Execution(ExecError::Block(reason))) => {
+            Err(format!("Divergence at block #{}: fees — {}", index, reason))
+        }
+        Err(BlockRejection::StateRoot { claimed, computed }) => {
+            // The rejected block left the chain's ledger untouched
+            let before = &chain.ledger;
+            let mut replayed = before.clone();
+            let changed = replayed.execute_block(&block).map(|undo| undo.balances.len()).unwrap_or(0);
+            Err(format!(
+                "Divergence at block #{}: the block records state root {}, the replay computes {}\n  \
+                 Replay of the block: fees {:?}, tail reward {}, xsx_circulating {}, {} balance(s) changed",
+                index,
+                claimed,
+                computed,
+                block.fees_collected,
+                replayed.xsx_circulating - before.xsx_circulating,
+                replayed.xsx_circulating,
+                changed
+            ))
+        }
+        Err(e) => Err(format!("Block #{} does not replay: {}", index, e)),
+    }
+}
+
+/// Compares the state the exporting node recorded with the replayed one.
+fn compare_state(replayed: &mut Ledger, recorded: StateSnapshot, height: u64) -> Result<(), String> {
+    let mut recorded = Ledger::from_parts(recorded.balances, recorded.stakes, recorded.ledger)?;
+    let mut diverged = Vec::new();
+
+    let keys: BTreeSet<(&String, &String)> = recorded.balances
+        .iter()
+        .chain(&replayed.balances)
+        .flat_map(|(addr, assets)| assets.keys().map(move |asset| (addr, asset)))
+        .collect();
+    let balance = |ledger: &Ledger, addr: &str, asset: &str| ledger.balances.get(addr).and_then(|m| m.get(asset)).copied();
+    let differing: Vec<String> = keys
+        .into_iter()
+        .filter(|(addr, asset)| balance(&recorded, addr, asset) != balance(&*replayed, addr, asset))
+        .map(|(addr, asset)| {
+            format!(
+                "balance {} {}: recorded {:?}, replayed {:?}",
+                addr,
+                asset,
+                balance(&recorded, addr, asset).unwrap_or((0, 0)),
+                balance(&*replayed, addr, asset).unwrap_or((0, 0))
+            )
+        })
+        .collect();
+    if differing.len() > MAX_REPORTED_BALANCES {
+        diverged.extend(differing[..MAX_REPORTED_BALANCES].iter().cloned());
+        diverged.push(format!("... and {} more balance(s)", differing.len() - MAX_REPORTED_BALANCES));
+    } else {
+        diverged.extend(differing);
+    }
+
+    if recorded.xsx_circulating != replayed.xsx_circulating {
+        diverged.push(format!(
+            "xsx_circulating (tail rewards): recorded {}, replayed {}",
+            recorded.xsx_circulating, replayed.xsx_circulating
+        ));
+    }
+    if recorded.xsx_burned != replayed.xsx_burned {
+        diverged.push(format!("xsx_burned: recorded {}, replayed {}", recorded.xsx_burned, replayed.xsx_burned));
+    }
+    if recorded.treasury != replayed.treasury {
+        diverged.push(format!("treasury: recorded {:?}, replayed {:?}", recorded.treasury, replayed.treasury));
+    }
+    if recorded.staked != replayed.staked || recorded.unbonding != replayed.unbonding {
+        diverged.push("stakes or unbonding entries differ".to_string());
+    }
+    if diverged.is_empty() && recorded.state_root() != replayed.state_root() {
+        diverged.push("validator set or keys differ".to_string());
+    }
+
+    if diverged.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "Divergence at block #{}: the recorded state does not match the replay\n  {}",
+        height,
+        diverged.join("\n  ")
+    ))
+}
@@ -0,0 +1,387 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use libp2p::Multiaddr;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::genesis::GENESIS_FILE;
+use crate::keystore::KEYSTORE_FILE;
+use crate::legacy::BLOCK_LOG_FILE;
+use crate::snapshot::SNAPSHOT_DIR;
+use crate::storage::DB_FILE;
+use crate::{MAX_REORG_DEPTH, STATE_FILE};
+
+pub const CONFIG_FILE: &str = "config.json";
+
+pub const DEFAULT_DATA_DIR: &str = "data";
+const DEFAULT_P2P_LISTEN: &str = "/ip4/0.0.0.0/tcp/4001";
+const DEFAULT_RPC_ADDR: &str = "0.0.0.0:9933";
+const DEFAULT_KEEP_BLOCKS: u64 = 10_000;
+
+const USAGE: &str = "\
+Usage: methalox_end_game [OPTIONS]
+       methalox_end_game keys generate [OPTIONS]
+       methalox_end_game snapshot export [OPTIONS]
+       methalox_end_game chain <export|import> [OPTIONS]
+
+Options:
+  --config <PATH>            Config file (default: <data-dir>/config.json if present)
+  --data-dir <DIR>           Directory for chain state and keys (default: data)
+  --listen <MULTIADDR>       P2P listen address; repeat for several (default: /ip4/0.0.0.0/tcp/4001)
+  --rpc-addr <ADDR>          JSON-RPC bind address (default: 0.0.0.0:9933)
+  --bootstrap <MULTIADDR>    Peer to dial at startup; repeat for several
+  --genesis <PATH>           Genesis spec (default: <data-dir>/genesis.json, else built-in dev chain)
+  --validator-key <PATH>     Validator keystore (default: <data-dir>/validator.keystore if present)
+  --password-file <PATH>     Keystore password file (default: $METHALOX_KEYSTORE_PASSWORD or prompt)
+  --block-interval-ms <MS>   How often to attempt block production (default: one slot)
+  --storage <BACKEND>        kv (on-disk database) or memory (nothing persisted) (default: kv)
+  --history <MODE>           full (all blocks), pruned (recent block bodies) or archive
+                             (all blocks plus balances at every height) (default: full)
+  --keep-blocks <N>          Block bodies a pruned node keeps (default: 10000)
+  --snapshot-interval <N>    Write a state snapshot every N blocks (default: off)
+  --fast-sync <BLOCK_HASH>   Start from a peer's snapshot at this trusted block instead of genesis
+  --log-level <LEVEL>        error, warn, info, debug or trace (default: info)
+  -h, --help                 Print this help";
+
+/// Node settings as they appear in the config file. Every field is optional
+/// so a file only needs to list what it overrides.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub data_dir: Option<PathBuf>,
+    pub listen: Option<Vec<String>>,
+    pub rpc_addr: Option<String>,
+    pub bootstrap: Option<Vec<String>>,
+    pub genesis: Option<PathBuf>,
+    pub validator_key: Option<PathBuf>,
+    pub password_file: Option<PathBuf>,
+    pub block_interval_ms: Option<u64>,
+    pub storage: Option<String>,
+    pub history: Option<String>,
+    pub keep_blocks: Option<u64>,
+    pub snapshot_interval: Option<u64>,
+    pub fast_sync: Option<String>,
+    pub log_level: Option<String>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?;
+        serde_json::from_slice(&data).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    /// Fields set in `other` take precedence.
+    fn merge(self, other: ConfigFile) -> Self {
+        Self {
+            data_dir: other.data_dir.or(self.data_dir),
+            listen: other.listen.or(self.listen),
+            rpc_addr: other.rpc_addr.or(self.rpc_addr),
+            bootstrap: other.bootstrap.or(self.bootstrap),
+            genesis: other.genesis.or(self.genesis),
+            validator_key: other.validator_key.or(self.validator_key),
+            password_file: other.password_file.or(self.password_file),
+            block_interval_ms: other.block_interval_ms.or(self.block_interval_ms),
+            storage: other.storage.or(self.storage),
+            history: other.history.or(self.history),
+            keep_blocks: other.keep_blocks.or(self.keep_blocks),
+            snapshot_interval: other.snapshot_interval.or(self.snapshot_interval),
+            fast_sync: other.fast_sync.or(self.fast_sync),
+            log_level: other.log_level.or(self.log_level),
+        }
+    }
+}
+
+/// Where the chain is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// Embedded key-value database in the data directory.
+    Kv,
+    /// In-memory only; the chain starts from genesis on every run.
+    Memory,
+}
+
+/// How much block history the node keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryMode {
+    /// Every block; state only at the tip.
+    Full,
+    /// Headers of every block, bodies and receipts of the last `keep_blocks` only.
+    Pruned { keep_blocks: u64 },
+    /// Every block plus every balance at every height.
+    Archive,
+}
+
+/// Validated node configuration: CLI flags over the config file over defaults.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    pub data_dir: PathBuf,
+    pub listen: Vec<Multiaddr>,
+    pub rpc_addr: SocketAddr,
+    pub bootstrap: Vec<Multiaddr>,
+    pub genesis: Option<PathBuf>,
+    pub validator_key: Option<PathBuf>,
+    pub password_file: Option<PathBuf>,
+    pub block_interval_ms: Option<u64>,
+    pub storage: StorageBackend,
+    pub history: HistoryMode,
+    pub snapshot_interval: Option<u64>,
+    pub fast_sync: Option<String>,
+    pub log_level: LevelFilter,
+}
+
+impl NodeConfig {
+    /// Parses command-line arguments (without the program name). Returns
+    /// `Ok(None)` when only help was requested.
+    pub fn from_args(args: &[String]) -> Result<Option<Self>, String> {
+        let mut cli = ConfigFile::default();
+        let mut config_path: Option<PathBuf> = None;
+
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            if flag == "-h" || flag == "--help" {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            let mut value = || iter.next().cloned().ok_or_else(|| format!("Missing value for {}\n\n{}", flag, USAGE));
+            match flag.as_str() {
+                "--config" => config_path = Some(PathBuf::from(value()?)),
+                "--data-dir" => cli.data_dir = Some(PathBuf::from(value()?)),
+                "--listen" => cli.listen.get_or_insert_with(Vec::new).push(value()?),
+                "--rpc-addr" => cli.rpc_addr = Some(value()?),
+                "--bootstrap" => cli.bootstrap.get_or_insert_with(Vec::new).push(value()?),
+                "--genesis" => cli.genesis = Some(PathBuf::from(value()?)),
+                "--validator-key" => cli.validator_key = Some(PathBuf::from(value()?)),
+                "--password-file" => cli.password_file = Some(PathBuf::from(value()?)),
+                "--block-interval-ms" => {
+                    let raw = value()?;
+                    let ms = raw.parse().map_err(|_| format!("Invalid --block-interval-ms: {}", raw))?;
+                    cli.block_interval_ms = Some(ms);
+                }
+                "--storage" => cli.storage = Some(value()?),
+                "--history" => cli.history = Some(value()?),
+                "--keep-blocks" => {
+                    let raw = value()?;
+                    let blocks = raw.parse().map_err(|_| format!("Invalid --keep-blocks: {}", raw))?;
+                    cli.keep_blocks = Some(blocks);
+                }
+                "--snapshot-interval" => {
+                    let raw = value()?;
+                    let blocks = raw.parse().map_err(|_| format!("Invalid --snapshot-interval: {}", raw))?;
+                    cli.snapshot_interval = Some(blocks);
+                }
+                "--fast-sync" => cli.fast_sync = Some(value()?),
+                "--log-level" => cli.log_level = Some(value()?),
+                other => return Err(format!("Unknown argument: {}\n\n{}", other, USAGE)),
+            }
+        }
+
+        let file = match config_path {
+            Some(path) => ConfigFile::load(&path)?,
+            None => {
+                let data_dir = cli.data_dir.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR));
+                let path = data_dir.join(CONFIG_FILE);
+                if path.exists() {
+                    ConfigFile::load(&path)?
+                } else {
+                    ConfigFile::default()
+                }
+            }
+        };
+
+        Self::resolve(file.merge(cli)).map(Some)
+    }
+
+    fn resolve(raw: ConfigFile) -> Result<Self, String> {
+        let listen = raw.listen
+            .unwrap_or_else(|| vec![DEFAULT_P2P_LISTEN.to_string()])
+            .iter()
+            .map(|a| parse_multiaddr("listen", a))
+            .collect::<Result<Vec<_>, _>>()?;
+        if listen.is_empty() {
+            return Err("At least one listen address is required".to_string());
+        }
+
+        let bootstrap = raw.bootstrap
+            .unwrap_or_default()
+            .iter()
+            .map(|a| parse_multiaddr("bootstrap", a))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let rpc_raw = raw.rpc_addr.unwrap_or_else(|| DEFAULT_RPC_ADDR.to_string());
+        let rpc_addr = rpc_raw.parse().map_err(|_| format!("Invalid rpc_addr: {}", rpc_raw))?;
+
+        if raw.block_interval_ms == Some(0) {
+            return Err("block_interval_ms must be greater than zero".to_string());
+        }
+
+        let storage = match raw.storage.as_deref().unwrap_or("kv") {
+            "kv" => StorageBackend::Kv,
+            "memory" => StorageBackend::Memory,
+            other => return Err(format!("Invalid storage: {} (expected kv or memory)", other)),
+        };
+
+        let history = match raw.history.as_deref().unwrap_or("full") {
+            "full" => HistoryMode::Full,
+            "pruned" => {
+                let keep_blocks = raw.keep_blocks.unwrap_or(DEFAULT_KEEP_BLOCKS);
+                // Reverting a block during a reorg needs its body
+                if keep_blocks < MAX_REORG_DEPTH {
+                    return Err(format!("keep_blocks must be at least {} (the reorg depth)", MAX_REORG_DEPTH));
+                }
+                HistoryMode::Pruned { keep_blocks }
+            }
+            "archive" => HistoryMode::Archive,
+            other => return Err(format!("Invalid history: {} (expected full, pruned or archive)", other)),
+        };
+        if raw.keep_blocks.is_some() && !matches!(history, HistoryMode::Pruned { .. }) {
+            return Err("keep_blocks only applies with history = pruned".to_string());
+        }
+        if history == HistoryMode::Archive && raw.fast_sync.is_some() {
+            return Err("An archive node needs every block since genesis and cannot fast sync".to_string());
+        }
+
+        if raw.snapshot_interval == Some(0) {
+            return Err("snapshot_interval must be greater than zero".to_string());
+        }
+
+        if let Some(hash) = &raw.fast_sync {
+            if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(format!("Invalid fast_sync block hash: {}", hash));
+            }
+        }
+
+        let level_raw = raw.log_level.unwrap_or_else(|| "info".to_string());
+        let log_level = level_raw.parse().map_err(|_| format!("Invalid log_level: {}", level_raw))?;
+
+        Ok(Self {
+            data_dir: raw.data_dir.unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR)),
+            listen,
+            rpc_addr,
+            bootstrap,
+            genesis: raw.genesis,
+            validator_key: raw.validator_key,
+            password_file: raw.password_file,
+            block_interval_ms: raw.block_interval_ms,
+            storage,
+            history,
+            snapshot_interval: raw.snapshot_interval,
+            fast_sync: raw.fast_sync.map(|h| h.to_ascii_lowercase()),
+            log_level,
+        })
+    }
+
+    /// Creates the data directory if it does not exist yet.
+    pub fn prepare_data_dir(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.data_dir)
+            .map_err(|e| format!("Cannot create data directory {}: {}", self.data_dir.display(), e))
+    }
+
+    pub fn state_path(&self) -> PathBuf {
+        self.data_dir.join(STATE_FILE)
+    }
+
+    pub fn block_log_path(&self) -> PathBuf {
+        self.data_dir.join(BLOCK_LOG_FILE)
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.data_dir.join(DB_FILE)
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.data_dir.join(SNAPSHOT_DIR)
+    }
+
+    /// Explicit keystore path, or `validator.keystore` in the data directory if it exists.
+    pub fn keystore_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.validator_key {
+            return Some(path.clone());
+        }
+        let default = self.data_dir.join(KEYSTORE_FILE);
+        default.exists().then_some(default)
+    }
+
+    /// Explicit genesis path, or `genesis.json` inside the data directory.
+    pub fn genesis_path(&self) -> PathBuf {
+        self.genesis.clone().unwrap_or_else(|| self.data_dir.join(GENESIS_FILE))
+    }
+}
+
+fn parse_multiaddr(field: &str, raw: &str) -> Result<Multiaddr, String> {
+    raw.parse().map_err(|e| format!("Invalid {} address {}: {}", field, raw, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Parses `list` with a data directory that holds no config file.
+    fn parse(list: &[&str]) -> Result<NodeConfig, String> {
+        let mut all = args(&["--data-dir", "/nonexistent/methalox-test-data"]);
+        all.extend(args(list));
+        NodeConfig::from_args(&all).map(|config| config.expect("Not a help request"))
+    }
+
+    fn scratch_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("methalox-config-{}-{}.json", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_override_the_file_which_overrides_defaults() {
+        let path = scratch_file(
+            "precedence",
+            r#"{ "rpc_addr": "127.0.0.1:1000", "history": "pruned", "keep_blocks": 500, "log_level": "warn" }"#,
+        );
+        let config = NodeConfig::from_args(&args(&["--config", path.to_str().unwrap(), "--rpc-addr", "127.0.0.1:2000"]))
+            .unwrap()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.rpc_addr, "127.0.0.1:2000".parse().unwrap());
+        assert_eq!(config.history, HistoryMode::Pruned { keep_blocks: 500 });
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.listen, vec![DEFAULT_P2P_LISTEN.parse::<Multiaddr>().unwrap()]);
+        assert_eq!(config.storage, StorageBackend::Kv);
+        assert_eq!(config.data_dir, PathBuf::from(DEFAULT_DATA_DIR));
+
+        let defaults = parse(&[]).unwrap();
+        assert_eq!(defaults.rpc_addr, DEFAULT_RPC_ADDR.parse().unwrap());
+        assert_eq!(defaults.history, HistoryMode::Full);
+        assert_eq!(defaults.log_level, LevelFilter::Info);
+    }
+
+    #[test]
+    fn unknown_flags_and_fields_are_errors() {
+        assert!(parse(&["--no-such-flag"]).unwrap_err().starts_with("Unknown argument: --no-such-flag"));
+        assert!(parse(&["--rpc-addr"]).unwrap_err().starts_with("Missing value for --rpc-addr"));
+
+        let path = scratch_file("unknown", r#"{ "rpc_adr": "127.0.0.1:1000" }"#);
+        let err = parse(&["--config", path.to_str().unwrap()]).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.starts_with("Invalid config file"));
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let err = parse(&["--history", "pruned", "--keep-blocks", "63"]).unwrap_err();
+        assert_eq!(err, format!("keep_blocks must be at least {} (the reorg depth)", MAX_REORG_DEPTH));
+        let config = parse(&["--history", "pruned", "--keep-blocks", "64"]).unwrap();
+        assert_eq!(config.history, HistoryMode::Pruned { keep_blocks: 64 });
+
+        assert!(parse(&["--keep-blocks", "100"]).is_err());
+        assert!(parse(&["--keep-blocks", "many"]).unwrap_err().starts_with("Invalid --keep-blocks"));
+        assert!(parse(&["--storage", "disk"]).unwrap_err().starts_with("Invalid storage"));
+        assert!(parse(&["--rpc-addr", "localhost"]).unwrap_err().starts_with("Invalid rpc_addr"));
+        assert!(parse(&["--block-interval-ms", "0"]).is_err());
+        assert!(parse(&["--fast-sync", "abc"]).unwrap_err().starts_with("Invalid fast_sync"));
+        assert!(parse(&["--history", "archive", "--fast-sync", &"ab".repeat(32)]).is_err());
+    }
+}
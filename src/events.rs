@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{Block, Transaction};
+
+// Slow subscribers that fall further behind than this skip the missed events
+const EVENT_BUFFER: usize = 256;
+
+/// Block header as pushed to subscribers: everything but the transaction bodies.
+#[derive(Serialize, Clone, Debug)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub slot: u64,
+    pub timestamp: u64,
+    pub hash: String,
+    pub prev_hash: String,
+    pub validator: String,
+    pub fees_collected: HashMap<String, u64>,
+    pub tx_root: String,
+    pub state_root: String,
+    pub tx_count: usize,
+}
+
+impl From<&Block> for BlockHeader {
+    fn from(block: &Block) -> Self {
+        Self {
+            index: block.index,
+            slot: block.slot,
+            timestamp: block.timestamp,
+            hash: block.hash.clone(),
+            prev_hash: block.prev_hash.clone(),
+            validator: block.validator.clone(),
+            fees_collected: block.fees_collected.clone(),
+            tx_root: block.tx_root.clone(),
+            state_root: block.state_root.clone(),
+            tx_count: block.transactions.len(),
+        }
+    }
+}
+
+/// One account/asset balance touched by a block.
+#[derive(Serialize, Clone, Debug)]
+pub struct BalanceChange {
+    pub address: String,
+    pub asset: String,
+    pub balance: u64,
+    pub nonce: u64,
+    pub prev_balance: u64,
+    pub prev_nonce: u64,
+}
+
+/// A block that just became the chain tip, with the balances it changed. When
+/// `removed` is set the block was instead reverted by a reorg, and `changes`
+/// were undone.
+#[derive(Clone, Debug)]
+pub struct AppliedBlock {
+    pub header: BlockHeader,
+    pub changes: Vec<BalanceChange>,
+    pub removed: bool,
+}
+
+/// Fan-out of chain events to RPC subscribers. Sending never blocks block
+/// application; events with no subscribers are dropped.
+pub struct ChainEvents {
+    blocks: broadcast::Sender<Arc<AppliedBlock>>,
+    transactions: broadcast::Sender<Transaction>,
+    finalized: broadcast::Sender<BlockHeader>,
+    finalized_height: u64,
+}
+
+impl ChainEvents {
+    /// `finalized_height` is the last height already final, so it is not announced again.
+    pub fn new(finalized_height: u64) -> Self {
+        Self {
+            blocks: broadcast::channel(EVENT_BUFFER).0,
+            transactions: broadcast::channel(EVENT_BUFFER).0,
+            finalized: broadcast::channel(EVENT_BUFFER).0,
+            finalized_height,
+        }
+    }
+
+    pub fn block_applied(&self, block: AppliedBlock) {
+        let _ = self.blocks.send(Arc::new(block));
+    }
+
+    pub fn transaction_added(&self, tx: &Transaction) {
+        let _ = self.transactions.send(tx.clone());
+    }
+
+    /// Announces `block` as final unless a block at or above its height already was.
+    pub fn block_finalized(&mut self, block: &Block) {
+        if block.index <= self.finalized_height {
+            return;
+        }
+        self.finalized_height = block.index;
+        let _ = self.finalized.send(block.into());
+    }
+
+    /// Treats every block up to `height` as already announced.
+    pub fn skip_finalized_to(&mut self, height: u64) {
+        self.finalized_height = self.finalized_height.max(height);
+    }
+
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<Arc<AppliedBlock>> {
+        self.blocks.subscribe()
+    }
+
+    pub fn subscribe_transactions(&self) -> broadcast::Receiver<Transaction> {
+        self.transactions.subscribe()
+    }
+
+    pub fn subscribe_finalized(&self) -> broadcast::Receiver<BlockHeader> {
+        self.finalized.subscribe()
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use ed25519_dalek::VerifyingKey;
+use log::info;
+use schnorrkel::keys::PublicKey;
+
+use crate::keystore::NodeKeys;
+use crate::{parse_signing_pubkey, parse_vrf_pubkey, DEFAULT_SLOT_SECONDS, DEFAULT_UNBONDING_BLOCKS, SUPPLY_CAP};
+
+pub const GENESIS_FILE: &str = "genesis.json";
+pub const DEV_CHAIN_ID: &str = "methalox-dev";
+
+const DEV_GENESIS_TIMESTAMP: u64 = 1_767_225_600; // 2026-01-01T00:00:00Z
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GenesisValidator {
+    pub address: String,
+    pub stake: u64,
+    /// Hex-encoded schnorrkel VRF public key.
+    pub vrf_pubkey: String,
+    /// Hex-encoded ed25519 key the validator signs blocks with.
+    pub signing_pubkey: String,
+}
+
+/// Everything every node must agree on before block 1. Maps are ordered so
+/// the spec hashes identically on every machine.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GenesisSpec {
+    pub chain_id: String,
+    pub timestamp: u64,
+    #[serde(default = "default_unbonding_period")]
+    pub unbonding_period: u64,
+    /// Seconds per slot; slot 0 starts at `timestamp`.
+    #[serde(default = "default_slot_duration")]
+    pub slot_duration: u64,
+    /// address -> asset -> spendable amount
+    pub allocations: BTreeMap<String, BTreeMap<String, u64>>,
+    pub validators: Vec<GenesisValidator>,
+}
+
+fn default_unbonding_period() -> u64 {
+    DEFAULT_UNBONDING_BLOCKS
+}
+
+fn default_slot_duration() -> u64 {
+    DEFAULT_SLOT_SECONDS
+}
+
+impl GenesisSpec {
+    /// Built-in development chain, used when no genesis file is present. Its
+    /// only validator is the demo key, so a lone dev node can produce blocks.
+    pub fn dev() -> Self {
+        let (address, vrf_pubkey) = NodeKeys::demo_public_keys();
+        let mut allocations = BTreeMap::new();
+        allocations.insert(
+            address.clone(),
+            [("XSX".to_string(), 20_990_000_000u64)].into_iter().collect(),
+        );
+
+        Self {
+            chain_id: DEV_CHAIN_ID.to_string(),
+            timestamp: DEV_GENESIS_TIMESTAMP,
+            unbonding_period: DEFAULT_UNBONDING_BLOCKS,
+            slot_duration: DEFAULT_SLOT_SECONDS,
+            allocations,
+            validators: vec![GenesisValidator {
+                signing_pubkey: address.clone(),
+                address,
+                stake: 10_000_000,
+                vrf_pubkey,
+            }],
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Cannot read genesis file {}: {}", path.display(), e))?;
+        let spec: Self = serde_json::from_slice(&data)
+            .map_err(|e| format!("Invalid genesis file {}: {}", path.display(), e))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Loads the spec at `path`, falling back to the dev chain if the file does not exist.
+    pub fn load_or_dev(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            info!("Genesis loaded from {}", path.display());
+            Self::load(path)
+        } else {
+            info!("No {} found — using built-in {} genesis", path.display(), DEV_CHAIN_ID);
+            Ok(Self::dev())
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chain_id.is_empty() {
+            return Err("Genesis chain_id must not be empty".to_string());
+        }
+        if self.slot_duration == 0 {
+            return Err("Genesis slot_duration must be at least 1 second".to_string());
+        }
+        if self.validators.is_empty() {
+            return Err("Genesis must contain at least one validator".to_string());
+        }
+
+        let mut xsx_total: u64 = 0;
+        for assets in self.allocations.values() {
+            if let Some(amount) = assets.get("XSX") {
+                xsx_total = xsx_total.checked_add(*amount).ok_or("Genesis XSX allocation overflows")?;
+            }
+        }
+
+        // Two validators sharing a key would win the same VRF draws or sign for each other
+        let mut vrf_keys = HashSet::new();
+        let mut signing_keys = HashSet::new();
+        for v in &self.validators {
+            if v.stake == 0 {
+                return Err(format!("Genesis validator {} has zero stake", v.address));
+            }
+            let vrf_pubkey = Self::parse_vrf_pubkey(&v.vrf_pubkey)
+                .map_err(|e| format!("Genesis validator {}: {}", v.address, e))?;
+            if !vrf_keys.insert(vrf_pubkey.to_bytes()) {
+                return Err(format!("Genesis validator {}: vrf_pubkey is already used by another validator", v.address));
+            }
+            let signing_pubkey = Self::parse_signing_pubkey(&v.signing_pubkey)
+                .map_err(|e| format!("Genesis validator {}: {}", v.address, e))?;
+            if !signing_keys.insert(signing_pubkey.to_bytes()) {
+                return Err(format!("Genesis validator {}: signing_pubkey is already used by another validator", v.address));
+            }
+            if v.signing_pubkey != v.address {
+                return Err(format!("Genesis validator {}: signing_pubkey must be the validator's address", v.address));
+            }
+            xsx_total = xsx_total.checked_add(v.stake).ok_or("Genesis XSX allocation overflows")?;
+        }
+
+        if xsx_total > SUPPLY_CAP {
+            return Err(format!("Genesis allocates {} XSX, above the supply cap", xsx_total));
+        }
+
+        Ok(())
+    }
+
+    pub fn parse_vrf_pubkey(hex_key: &str) -> Result<PublicKey, String> {
+        let bytes = hex::decode(hex_key).map_err(|_| "VRF public key is not hex".to_string())?;
+        parse_vrf_pubkey(&bytes)
+    }
+
+    pub fn parse_signing_pubkey(hex_key: &str) -> Result<VerifyingKey, String> {
+        let bytes = hex::decode(hex_key).map_err(|_| "Signing public key is not hex".to_string())?;
+        parse_signing_pubkey(&bytes)
+    }
+
+    /// Whether this is exactly the built-in dev genesis; a custom genesis that
+    /// merely reuses its chain id does not count.
+    pub fn is_dev(&self) -> bool {
+        self.spec_hash() == Self::dev().spec_hash()
+    }
+
+    /// Hex SHA-256 over the bincode encoding of the spec; becomes the genesis block's `prev_hash`.
+    pub fn spec_hash(&self) -> String {
+        let serialized = bincode::serialize(self).expect("Genesis spec serializes");
+        hex::encode(Sha256::digest(&serialized))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use schnorrkel::{ExpansionMode, MiniSecretKey};
+
+    use super::*;
+
+    /// A second validator with its own keys, for a spec next to the dev one.
+    fn other_validator() -> GenesisValidator {
+        let address = hex::encode(SigningKey::from_bytes(&[7; 32]).verifying_key().to_bytes());
+        let vrf_secret = MiniSecretKey::from_bytes(&[7; 32]).unwrap().expand(ExpansionMode::Ed25519);
+        GenesisValidator {
+            signing_pubkey: address.clone(),
+            address,
+            stake: 1_000,
+            vrf_pubkey: hex::encode(vrf_secret.to_public().to_bytes()),
+        }
+    }
+
+    #[test]
+    fn validators_must_not_share_keys() {
+        let mut genesis = GenesisSpec::dev();
+        genesis.validators.push(other_validator());
+        assert!(genesis.validate().is_ok());
+
+        let mut shared_vrf = GenesisSpec::dev();
+        let mut second = other_validator();
+        second.vrf_pubkey = shared_vrf.validators[0].vrf_pubkey.clone();
+        shared_vrf.validators.push(second);
+        assert!(shared_vrf.validate().unwrap_err().contains("vrf_pubkey is already used"));
+
+        // The signing key is the address, so this is also the same validator listed twice
+        let mut shared_signing = GenesisSpec::dev();
+        let mut second = shared_signing.validators[0].clone();
+        second.vrf_pubkey = other_validator().vrf_pubkey;
+        shared_signing.validators.push(second);
+        assert!(shared_signing.validate().unwrap_err().contains("signing_pubkey is already used"));
+    }
+}
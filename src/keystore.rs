@@ -0,0 +1,362 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ed25519_dalek::SigningKey;
+use libp2p::{identity, PeerId};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use schnorrkel::keys::{ExpansionMode, MiniSecretKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+pub const KEYSTORE_FILE: &str = "validator.keystore";
+pub const P2P_KEY_FILE: &str = "p2p.key";
+pub const PASSWORD_ENV: &str = "METHALOX_KEYSTORE_PASSWORD";
+
+const KEYSTORE_VERSION: u32 = 1;
+const KDF_M_COST: u32 = 64 * 1024; // KiB
+const KDF_T_COST: u32 = 3;
+const KDF_P_COST: u32 = 1;
+
+// Publicly known seed used when no keystore is configured; only acceptable on dev chains
+const DEMO_SEED: [u8; 32] = [42u8; 32];
+
+const KEYS_USAGE: &str = "\
+Usage: methalox_end_game keys generate [OPTIONS]
+
+Options:
+  --data-dir <DIR>         Data directory (default: data)
+  --output <PATH>          Keystore path (default: <data-dir>/validator.keystore)
+  --password-file <PATH>   Read the password from a file instead of prompting";
+
+/// The node's secret keys: the VRF key for leader election, the ed25519
+/// account key that owns its funds and signs its blocks, and its libp2p identity.
+pub struct NodeKeys {
+    pub vrf_secret: SecretKey,
+    pub account: SigningKey,
+    pub p2p: identity::Keypair,
+}
+
+impl NodeKeys {
+    fn from_seeds(seeds: &KeySeeds) -> Result<Self, String> {
+        let vrf_secret = MiniSecretKey::from_bytes(&seeds.vrf)
+            .map_err(|_| "Invalid VRF seed".to_string())?
+            .expand(ExpansionMode::Ed25519);
+        let p2p = identity::Keypair::ed25519_from_bytes(seeds.p2p)
+            .map_err(|e| format!("Invalid libp2p key: {}", e))?;
+        Ok(Self {
+            vrf_secret,
+            account: SigningKey::from_bytes(&seeds.account),
+            p2p,
+        })
+    }
+
+    /// Keys derived from the public demo seed. The libp2p identity is not
+    /// part of the seed so that several dev nodes get distinct PeerIds.
+    pub fn demo(p2p: identity::Keypair) -> Self {
+        Self {
+            vrf_secret: MiniSecretKey::from_bytes(&DEMO_SEED)
+                .expect("Demo seed is 32 bytes")
+                .expand(ExpansionMode::Ed25519),
+            account: SigningKey::from_bytes(&DEMO_SEED),
+            p2p,
+        }
+    }
+
+    /// Address and VRF public key of the demo keys, which validate the built-in dev chain.
+    pub fn demo_public_keys() -> (String, String) {
+        let demo = Self::demo(identity::Keypair::generate_ed25519());
+        (demo.address(), demo.vrf_pubkey())
+    }
+
+    /// Account address: hex of the ed25519 public key, as used in `Transaction::from`.
+    pub fn address(&self) -> String {
+        hex::encode(self.account.verifying_key().to_bytes())
+    }
+
+    pub fn vrf_pubkey(&self) -> String {
+        hex::encode(self.vrf_secret.to_public().to_bytes())
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from(self.p2p.public())
+    }
+}
+
+/// Plaintext inside the keystore; only ever held in zeroizing buffers.
+#[derive(Serialize, Deserialize)]
+struct KeySeeds {
+    vrf: [u8; 32],
+    account: [u8; 32],
+    p2p: [u8; 32],
+}
+
+impl Zeroize for KeySeeds {
+    fn zeroize(&mut self) {
+        self.vrf.zeroize();
+        self.account.zeroize();
+        self.p2p.zeroize();
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    algorithm: String,
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+/// On-disk keystore. Public keys are stored in the clear so operators can
+/// read them without the password; the seeds are encrypted with
+/// XChaCha20-Poly1305 under an Argon2id-derived key.
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    address: String,
+    vrf_pubkey: String,
+    signing_pubkey: String,
+    peer_id: String,
+    kdf: KdfParams,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(password: &str, kdf: &KdfParams) -> Result<Zeroizing<[u8; 32]>, String> {
+    if kdf.algorithm != "argon2id" {
+        return Err(format!("Unsupported keystore KDF: {}", kdf.algorithm));
+    }
+    let salt = hex::decode(&kdf.salt).map_err(|_| "Keystore salt is not hex".to_string())?;
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| format!("Invalid keystore KDF parameters: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), &salt, key.as_mut())
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// Generates fresh keys and writes them to `path`, encrypted with `password`.
+/// Refuses to overwrite an existing file.
+pub fn generate(path: &Path, password: &str) -> Result<NodeKeys, String> {
+    if path.exists() {
+        return Err(format!("{} already exists; refusing to overwrite it", path.display()));
+    }
+
+    let mut seeds = Zeroizing::new(KeySeeds { vrf: [0u8; 32], account: [0u8; 32], p2p: [0u8; 32] });
+    OsRng.fill_bytes(&mut seeds.vrf);
+    OsRng.fill_bytes(&mut seeds.account);
+    OsRng.fill_bytes(&mut seeds.p2p);
+    let keys = NodeKeys::from_seeds(&seeds)?;
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let kdf = KdfParams {
+        algorithm: "argon2id".to_string(),
+        salt: hex::encode(salt),
+        m_cost: KDF_M_COST,
+        t_cost: KDF_T_COST,
+        p_cost: KDF_P_COST,
+    };
+    let key = derive_key(password, &kdf)?;
+
+    let mut nonce = [0u8; 24];
+    OsRng.fill_bytes(&mut nonce);
+    let plaintext = Zeroizing::new(bincode::serialize(&*seeds).map_err(|e| e.to_string())?);
+    let ciphertext = XChaCha20Poly1305::new(key.as_ref().into())
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| "Keystore encryption failed".to_string())?;
+
+    let file = KeystoreFile {
+        version: KEYSTORE_VERSION,
+        address: keys.address(),
+        vrf_pubkey: keys.vrf_pubkey(),
+        signing_pubkey: keys.address(),
+        peer_id: keys.peer_id().to_string(),
+        kdf,
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    };
+    let json = serde_json::to_vec_pretty(&file).map_err(|e| e.to_string())?;
+    write_private(path, &json)?;
+
+    Ok(keys)
+}
+
+/// Decrypts the keystore at `path` and checks the keys match its public header.
+pub fn load(path: &Path, password: &str) -> Result<NodeKeys, String> {
+    let data = fs::read(path).map_err(|e| format!("Cannot read keystore {}: {}", path.display(), e))?;
+    let file: KeystoreFile = serde_json::from_slice(&data)
+        .map_err(|e| format!("Invalid keystore {}: {}", path.display(), e))?;
+    if file.version != KEYSTORE_VERSION {
+        return Err(format!("Unsupported keystore version {} in {}", file.version, path.display()));
+    }
+
+    let key = derive_key(password, &file.kdf)?;
+    let nonce = hex::decode(&file.nonce).map_err(|_| "Keystore nonce is not hex".to_string())?;
+    if nonce.len() != 24 {
+        return Err("Keystore nonce must be 24 bytes".to_string());
+    }
+    let ciphertext = hex::decode(&file.ciphertext).map_err(|_| "Keystore ciphertext is not hex".to_string())?;
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(key.as_ref().into())
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| format!("Wrong password for keystore {}", path.display()))?,
+    );
+    let seeds: Zeroizing<KeySeeds> = Zeroizing::new(
+        bincode::deserialize(&plaintext).map_err(|_| "Corrupt keystore contents".to_string())?,
+    );
+    let keys = NodeKeys::from_seeds(&seeds)?;
+
+    if keys.address() != file.address || keys.vrf_pubkey() != file.vrf_pubkey || keys.peer_id().to_string() != file.peer_id {
+        return Err(format!("Keystore {} public keys do not match its encrypted keys", path.display()));
+    }
+    Ok(keys)
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    file.write_all(data).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Loads the libp2p identity stored at `path`, creating it on first start so
+/// the PeerId stays stable across restarts.
+pub fn load_or_create_p2p_identity(path: &Path) -> Result<identity::Keypair, String> {
+    if path.exists() {
+        let data = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        return identity::Keypair::from_protobuf_encoding(&data)
+            .map_err(|e| format!("Invalid libp2p key {}: {}", path.display(), e));
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    let encoded = keypair.to_protobuf_encoding().map_err(|e| format!("Cannot encode libp2p key: {}", e))?;
+    write_private(path, &encoded)?;
+    Ok(keypair)
+}
+
+/// Password from a file, the `METHALOX_KEYSTORE_PASSWORD` variable, or an
+/// interactive prompt, in that order.
+pub fn read_password(password_file: Option<&Path>, confirm: bool) -> Result<Zeroizing<String>, String> {
+    if let Some(path) = password_file {
+        let data = fs::read_to_string(path).map_err(|e| format!("Cannot read password file {}: {}", path.display(), e))?;
+        return Ok(Zeroizing::new(data.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(Zeroizing::new(password));
+    }
+
+    let password = Zeroizing::new(
+        rpassword::prompt_password("Keystore password: ").map_err(|e| format!("Cannot read password: {}", e))?,
+    );
+    if confirm {
+        let again = Zeroizing::new(
+            rpassword::prompt_password("Repeat password: ").map_err(|e| format!("Cannot read password: {}", e))?,
+        );
+        if *password != *again {
+            return Err("Passwords do not match".to_string());
+        }
+    }
+    Ok(password)
+}
+
+/// `keys generate`: creates a keystore and prints the public keys needed for
+/// a genesis entry or a Stake transaction.
+pub fn run_command(args: &[String]) -> Result<(), String> {
+    let Some((command, flags)) = args.split_first() else {
+        return Err(KEYS_USAGE.to_string());
+    };
+    if command != "generate" {
+        return Err(format!("Unknown keys command: {}\n\n{}", command, KEYS_USAGE));
+    }
+
+    let mut data_dir = PathBuf::from(crate::config::DEFAULT_DATA_DIR);
+    let mut output = None;
+    let mut password_file = None;
+    let mut iter = flags.iter();
+    while let Some(flag) = iter.next() {
+        if flag == "-h" || flag == "--help" {
+            println!("{}", KEYS_USAGE);
+            return Ok(());
+        }
+        let value = iter.next().ok_or_else(|| format!("Missing value for {}\n\n{}", flag, KEYS_USAGE))?;
+        match flag.as_str() {
+            "--data-dir" => data_dir = PathBuf::from(value),
+            "--output" => output = Some(PathBuf::from(value)),
+            "--password-file" => password_file = Some(PathBuf::from(value)),
+            other => return Err(format!("Unknown argument: {}\n\n{}", other, KEYS_USAGE)),
+        }
+    }
+
+    let path = output.unwrap_or_else(|| data_dir.join(KEYSTORE_FILE));
+    let password = read_password(password_file.as_deref(), true)?;
+    if password.is_empty() {
+        return Err("Keystore password must not be empty".to_string());
+    }
+    let keys = generate(&path, &password)?;
+
+    println!("Keystore written to {}", path.display());
+    println!("Address:        {}", keys.address());
+    println!("VRF public key: {}", keys.vrf_pubkey());
+    println!("Signing key:    {}", keys.address());
+    println!("Peer ID:        {}", keys.peer_id());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("methalox-keystore-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn p2p_identity_survives_a_restart() {
+        let dir = scratch_dir("p2p");
+        let path = dir.join("p2p.key");
+        let first = load_or_create_p2p_identity(&path).unwrap();
+        let second = load_or_create_p2p_identity(&path).unwrap();
+        assert_eq!(PeerId::from(first.public()), PeerId::from(second.public()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::write(&path, b"not a key").unwrap();
+        assert!(load_or_create_p2p_identity(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keystore_round_trip_needs_the_password() {
+        let dir = scratch_dir("keystore");
+        let path = dir.join("validator.keystore");
+        let generated = generate(&path, "correct horse").unwrap();
+        assert!(generate(&path, "correct horse").is_err());
+
+        let loaded = load(&path, "correct horse").unwrap();
+        assert_eq!(loaded.address(), generated.address());
+        assert_eq!(loaded.vrf_pubkey(), generated.vrf_pubkey());
+        assert_eq!(loaded.peer_id(), generated.peer_id());
+        assert!(matches!(load(&path, "wrong"), Err(e) if e.starts_with("Wrong password")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use bincode::Options;
+use log::warn;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::Block;
+
+pub const BLOCK_LOG_FILE: &str = "blocks.log";
+
+const CHECKSUM_LEN: usize = 32;
+
+// Readers for the checkpoint file and block log that held the chain before
+// the database. They are only used to import an old data directory once.
+
+/// Reads the blocks of an old block log. Each record is a u32 little-endian
+/// payload length, the SHA-256 of the payload, then the bincode-encoded block.
+/// A torn record at the end, left by a crash mid-write, is skipped.
+pub fn read_block_log(path: &Path) -> Result<Vec<Block>, String> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| format!("Cannot read block log {}: {}", path.display(), e))?;
+
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        match decode_record(&data[offset..]) {
+            Some((block, len)) => {
+                blocks.push(block);
+                offset += len;
+            }
+            None => {
+                warn!(
+                    "Block log {} has a torn record at byte {} — ignoring {} trailing bytes",
+                    path.display(), offset, data.len() - offset
+                );
+                break;
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+fn decode_record(data: &[u8]) -> Option<(Block, usize)> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let checksum = data.get(4..4 + CHECKSUM_LEN)?;
+    let payload = data.get(4 + CHECKSUM_LEN..4 + CHECKSUM_LEN + len)?;
+    if Sha256::digest(payload).as_slice() != checksum {
+        return None;
+    }
+    let block = bincode::deserialize(payload).ok()?;
+    Some((block, 4 + CHECKSUM_LEN + len))
+}
+
+pub enum Checkpoint {
+    Missing,
+    Valid(Vec<u8>),
+    /// Checksum did not match; holds the whole file.
+    Unverified(Vec<u8>),
+}
+
+/// Reads a checkpoint: the SHA-256 of the payload followed by the payload.
+pub fn read_checkpoint(path: &Path) -> Result<Checkpoint, String> {
+    if !path.exists() {
+        return Ok(Checkpoint::Missing);
+    }
+    let data = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    if data.len() >= CHECKSUM_LEN && Sha256::digest(&data[CHECKSUM_LEN..]).as_slice() == &data[..CHECKSUM_LEN] {
+        return Ok(Checkpoint::Valid(data[CHECKSUM_LEN..].to_vec()));
+    }
+    Ok(Checkpoint::Unverified(data))
+}
+
+// Layout of `chain_state.bin` as written by nodes from before chain IDs,
+// genesis files and state commitments. Only decoded to recognize the file.
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+enum UnversionedTxKind {
+    Transfer,
+    Stake { amount: u64, vrf_pubkey: Vec<u8> },
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct UnversionedTx {
+    from: String,
+    to: String,
+    amount: u64,
+    kind: UnversionedTxKind,
+    signature: Vec<u8>,
+    timestamp: u64,
+    nonce: u64,
+    commitment: String,
+    blinding_factor: u64,
+    asset: String,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct UnversionedBlock {
+    index: u64,
+    timestamp: u64,
+    transactions: Vec<UnversionedTx>,
+    prev_hash: String,
+    hash: String,
+    validator: String,
+    fees_collected: HashMap<String, u64>,
+    vrf_proof: Vec<u8>,
+    vrf_output: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct UnversionedState {
+    blocks: Vec<UnversionedBlock>,
+    balances: HashMap<String, HashMap<String, (u64, u64)>>,
+    treasury: HashMap<String, u64>,
+    xsx_circulating: u64,
+    tx_pool: Vec<UnversionedTx>,
+    validators: HashSet<String>,
+    staked: HashMap<String, u64>,
+    vrf_public_keys: HashMap<String, Vec<u8>>,
+    node_vrf_public_bytes: Vec<u8>,
+}
+
+/// True if `data` is a whole checkpoint in the unversioned layout. Those
+/// chains have no genesis to check them against, so they cannot be imported.
+pub fn is_unversioned_state(data: &[u8]) -> bool {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize::<UnversionedState>(data)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    enum Kind {
+        Transfer,
+    }
+
+    #[derive(Serialize)]
+    struct Tx {
+        from: String,
+        to: String,
+        amount: u64,
+        kind: Kind,
+        signature: Vec<u8>,
+        timestamp: u64,
+        nonce: u64,
+        commitment: String,
+        blinding_factor: u64,
+        asset: String,
+    }
+
+    /// A checkpoint as the first releases wrote it: one genesis block and a pending transfer.
+    fn unversioned_checkpoint() -> Vec<u8> {
+        let tx = Tx {
+            from: "alice".into(),
+            to: "bob".into(),
+            amount: 5,
+            kind: Kind::Transfer,
+            signature: vec![1; 64],
+            timestamp: 1,
+            nonce: 1,
+            commitment: String::new(),
+            blinding_factor: 0,
+            asset: "XSX".into(),
+        };
+        let genesis = (
+            0u64,
+            0u64,
+            Vec::<Tx>::new(),
+            "0".to_string(),
+            "ab".repeat(32),
+            "node_001".to_string(),
+            HashMap::<String, u64>::new(),
+            Vec::<u8>::new(),
+            Vec::<u8>::new(),
+        );
+        let balances = HashMap::from([("alice".to_string(), HashMap::from([("XSX".to_string(), (100u64, 0u64))]))]);
+        bincode::serialize(&(
+            vec![genesis],
+            balances,
+            HashMap::<String, u64>::new(),
+            100u64,
+            vec![tx],
+            HashSet::from(["node_001".to_string()]),
+            HashMap::from([("node_001".to_string(), 10u64)]),
+            HashMap::<String, Vec<u8>>::new(),
+            vec![2u8; 32],
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn recognizes_unversioned_checkpoints() {
+        let data = unversioned_checkpoint();
+        assert!(is_unversioned_state(&data));
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(!is_unversioned_state(&trailing));
+        assert!(!is_unversioned_state(&data[..data.len() - 1]));
+        assert!(!is_unversioned_state(b"not a checkpoint"));
+    }
+
+    #[test]
+    fn checkpoint_checksum_is_verified() {
+        let dir = std::env::temp_dir().join(format!("methalox-legacy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chain_state.bin");
+
+        let payload = b"payload".to_vec();
+        fs::write(&path, [Sha256::digest(&payload).as_slice(), &payload].concat()).unwrap();
+        assert!(matches!(read_checkpoint(&path).unwrap(), Checkpoint::Valid(p) if p == payload));
+
+        fs::write(&path, unversioned_checkpoint()).unwrap();
+        assert!(matches!(read_checkpoint(&path).unwrap(), Checkpoint::Unverified(_)));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(read_checkpoint(&path).unwrap(), Checkpoint::Missing));
+    }
+}
@@ -0,0 +1,26 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Minimal stdout logger: `[LEVEL] message`, filtered by the configured level.
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+pub fn init(level: LevelFilter) {
+    // Only fails if a logger is already installed, in which case keep it
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -1,17 +1,16 @@
 // בָּרוּךְ שֵׁם יֵשׁוּעַ הַמָּשִׁיחַ
 
-use std::collections::{HashMap, HashSet};
-use std::fs;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use futures::stream::StreamExt;
 
 use libp2p::{
-    gossipsub::{self, IdentTopic, MessageAuthenticity, IdentityTransform},
-    identity,
+    gossipsub::{self, IdentTopic, IdentityTransform, MessageAcceptance, MessageAuthenticity, MessageId, TopicHash},
     noise,
-    swarm::{SwarmBuilder, SwarmEvent},
+    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
     tcp,
     yamux,
     PeerId,
@@ -20,6 +19,7 @@ use libp2p::{
 use libp2p::core::upgrade;
 
 use tokio::signal;
+use tokio::sync::mpsc;
 use tokio::time;
 
 use bincode;
@@ -28,14 +28,46 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use schnorrkel::{
-    keys::{ExpansionMode, Keypair, MiniSecretKey, PublicKey, SecretKey},
+    keys::{Keypair, PublicKey, SecretKey},
     signing_context,
-    vrf::{VRFOutput, VRFProof},
+    vrf::{VRFInOut, VRFOutput, VRFProof},
 };
 
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
-
-use jsonrpsee::server::{ServerBuilder, RpcModule};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use jsonrpsee::server::ServerBuilder;
+
+use log::{debug, error, info, warn};
+
+mod chain_file;
+mod config;
+mod events;
+mod genesis;
+mod keystore;
+mod legacy;
+mod logger;
+mod mempool;
+mod migration;
+mod rpc;
+mod snapshot;
+mod state_tree;
+mod storage;
+mod sync;
+
+use config::{HistoryMode, NodeConfig, StorageBackend};
+use events::{AppliedBlock, BalanceChange, ChainEvents};
+use genesis::GenesisSpec;
+use keystore::NodeKeys;
+use legacy::Checkpoint;
+use mempool::Mempool;
+use rpc::{NetworkInfo, RpcContext};
+use snapshot::{SnapshotEvent, SnapshotStore, SnapshotSync};
+use state_tree::StateTree;
+use storage::{
+    BalanceEntry, ChainStorage, KvStorage, MemoryStorage, WriteBatch, META_ARCHIVE, META_BODY_FLOOR, META_LEDGER,
+    META_MEMPOOL, META_NODE_VRF_KEY, META_SCHEMA_VERSION,
+};
+use sync::{SyncBehaviour, SyncEvent, SyncManager};
 
 const STATE_FILE: &str = "chain_state.bin";
 
@@ -49,10 +81,38 @@ const XSX_BURN_RATE: f64 = 0.01; // 1% burn on founder XSX rake
 const BASE_TAIL_REWARD: u64 = 50;
 const CAP_TO_MINT_RATIO: u64 = 10_000_000;
 
+const DEFAULT_UNBONDING_BLOCKS: u64 = 1_000;
+const DEFAULT_SLOT_SECONDS: u64 = 1;
+
+// Prefix of every transaction's signing bytes, so a tx signature can never be a valid signature on anything else
+const TX_SIGNING_DOMAIN: &[u8] = b"methalox-tx-v1";
+
+// Dropped and failed transaction statuses remembered for get_transaction_status
+const MAX_TX_FAILURES: usize = 10_000;
+
+// Blocks deeper than this below the tip are final: no undo records are kept for them
+const MAX_REORG_DEPTH: u64 = 64;
+
+// Competing blocks kept for fork choice, per height and in total
+const MAX_SIDE_BLOCKS_PER_HEIGHT: usize = 4;
+const MAX_SIDE_BLOCKS: usize = 512;
+
+// Blocks handled per storage commit when pruning or building history at startup
+const HISTORY_BATCH_BLOCKS: u64 = 1_000;
+
+// Caps on a block's body, counted over the transactions' encoded bytes
+const MAX_BLOCK_TXS: usize = 4_096;
+const MAX_BLOCK_TX_BYTES: u64 = 1024 * 1024;
+
+// A gossiped block is its transactions, at most one fee entry per asset they use, and a small header
+const MAX_GOSSIP_MESSAGE_SIZE: usize = 2 * MAX_BLOCK_TX_BYTES as usize + 64 * 1024;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum TransactionKind {
     Transfer,
-    Stake { amount: u64, vrf_pubkey: Vec<u8> },
+    Stake { amount: u64, vrf_pubkey: Vec<u8>, signing_pubkey: Vec<u8> },
+    Unstake { amount: u64 },
+    Withdraw,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -69,9 +129,17 @@ struct Transaction {
     asset: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Unbonding {
+    amount: u64,
+    release_height: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Block {
     index: u64,
+    /// Slot the block was produced in; strictly increasing along a chain.
+    slot: u64,
     timestamp: u64,
     transactions: Vec<Transaction>,
     prev_hash: String,
@@ -80,217 +148,758 @@ struct Block {
     fees_collected: HashMap<String, u64>,
     vrf_proof: Vec<u8>,
     vrf_output: Vec<u8>,
+    /// Validator's ed25519 signature over `hash`.
+    signature: Vec<u8>,
+    /// Merkle root of the transaction hashes.
+    tx_root: String,
+    /// Commitment to the ledger after this block is applied.
+    state_root: String,
+}
+
+/// (address, asset, value before the block; `None` if the entry did not exist)
+type BalanceUndo = (String, String, Option<(u64, u64)>);
+
+/// Previous values of every ledger entry a block touched, enough to roll the
+/// block back without replaying from genesis.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct BlockUndo {
+    balances: Vec<BalanceUndo>,
+    treasury: Vec<(String, Option<u64>)>,
+    staked: Vec<(String, Option<u64>)>,
+    validators: Vec<(String, bool)>,
+    unbonding: Vec<(String, Option<Vec<Unbonding>>)>,
+    vrf_public_keys: Vec<(String, Option<Vec<u8>>)>,
+    signing_keys: Vec<(String, Option<VerifyingKey>)>,
+    xsx_circulating: u64,
+    xsx_burned: u64,
 }
 
+/// Whole chain as written to the checkpoint file before the database; only read to import an old data directory.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct MethaloxChainState {
+    chain_id: String,
     blocks: Vec<Block>,
     balances: HashMap<String, HashMap<String, (u64, u64)>>,
     treasury: HashMap<String, u64>,
     xsx_circulating: u64,
+    xsx_burned: u64,
     tx_pool: Vec<Transaction>,
     validators: HashSet<String>,
     staked: HashMap<String, u64>,
+    unbonding: HashMap<String, Vec<Unbonding>>,
+    unbonding_period: u64,
     vrf_public_keys: HashMap<String, Vec<u8>>,
+    signing_keys: HashMap<String, VerifyingKey>,
+    undo: Vec<BlockUndo>,
+    genesis_timestamp: u64,
+    slot_duration: u64,
     node_vrf_public_bytes: Vec<u8>,
 }
 
-struct MethaloxChain {
-    blocks: Vec<Block>,
+/// Ledger fields other than balances and stakes, stored as a single record.
+#[derive(Serialize, Deserialize)]
+struct LedgerRecord {
+    treasury: HashMap<String, u64>,
+    xsx_circulating: u64,
+    xsx_burned: u64,
+    validators: HashSet<String>,
+    unbonding: HashMap<String, Vec<Unbonding>>,
+    unbonding_period: u64,
+    vrf_public_keys: HashMap<String, Vec<u8>>,
+    signing_keys: HashMap<String, VerifyingKey>,
+}
+
+/// Ledger contents carried by a state snapshot.
+#[derive(Serialize, Deserialize)]
+struct StateSnapshot {
+    balances: Vec<BalanceEntry>,
+    stakes: Vec<(String, u64)>,
+    ledger: LedgerRecord,
+}
+
+/// One entry of the ledger, as committed to by the state root.
+#[derive(Serialize, Clone, PartialEq, Eq, Hash)]
+enum StateKey {
+    Balance(String, String),
+    Treasury(String),
+    Staked(String),
+    Validator(String),
+    Unbonding(String),
+    VrfKey(String),
+    SigningKey(String),
+    Circulating,
+    Burned,
+}
+
+/// The ledger's state tree and the entries changed since it was last updated.
+#[derive(Clone, Default)]
+struct StateCommitment {
+    tree: StateTree,
+    dirty: HashSet<StateKey>,
+    /// False until the tree has been filled from the whole ledger.
+    built: bool,
+}
+
+/// Values, from before the block being executed, of every entry it has
+/// touched so far; becomes the block's undo record.
+#[derive(Clone)]
+struct Journal {
+    balances: HashMap<(String, String), Option<(u64, u64)>>,
+    staked: HashMap<String, Option<u64>>,
+    validators: HashMap<String, bool>,
+    unbonding: HashMap<String, Option<Vec<Unbonding>>>,
+    vrf_public_keys: HashMap<String, Option<PublicKey>>,
+    signing_keys: HashMap<String, Option<VerifyingKey>>,
+    xsx_circulating: u64,
+    xsx_burned: u64,
+}
+
+/// Account and staking state that blocks mutate. Kept separate from the
+/// block list so it can be snapshotted, and changed in place with an undo
+/// record per block.
+#[derive(Clone)]
+struct Ledger {
     balances: HashMap<String, HashMap<String, (u64, u64)>>,
     treasury: HashMap<String, u64>,
     xsx_circulating: u64,
-    tx_pool: Vec<Transaction>,
+    /// Total XSX burned from the founder fee rake.
+    xsx_burned: u64,
     validators: HashSet<String>,
     staked: HashMap<String, u64>,
+    unbonding: HashMap<String, Vec<Unbonding>>,
+    unbonding_period: u64,
     vrf_public_keys: HashMap<String, PublicKey>,
-    node_address: String,
-    node_secret: SecretKey,
-    node_vrf_public: PublicKey,
+    signing_keys: HashMap<String, VerifyingKey>,
+    commitment: StateCommitment,
+    journal: Option<Journal>,
 }
 
-impl MethaloxChain {
-    fn new(node_address: String, node_secret_seed: [u8; 32]) -> Self {
-        let genesis_timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
+/// Where an included transaction landed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Receipt {
+    block_index: u64,
+    block_hash: String,
+    tx_index: usize,
+    fee: u64,
+    asset: String,
+}
 
-        let mut genesis = Block {
-            index: 0,
-            timestamp: genesis_timestamp,
-            transactions: vec![],
-            prev_hash: "0".to_string(),
-            hash: String::new(),
-            validator: FOUNDER_ADDRESS.to_string(),
-            fees_collected: HashMap::new(),
-            vrf_proof: vec![],
-            vrf_output: vec![],
-        };
-        genesis.hash = Self::hash_block(&genesis);
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum TxStatus {
+    Pending,
+    Included(Receipt),
+    /// Removed from the mempool without being included, e.g. no longer valid when a block was built.
+    Dropped { reason: String },
+    /// Made a block invalid, so the block was rejected.
+    Failed { block_index: u64, block_hash: String, reason: String },
+    Unknown,
+}
 
-        let mut balances = HashMap::new();
-        balances.insert(
-            FOUNDER_ADDRESS.to_string(),
-            [("XSX".to_string(), (21_000_000_000u64, 0u64))].into_iter().collect(),
-        );
+enum ExecError {
+    Tx { index: usize, reason: String },
+    Block(String),
+}
 
-        let mut validators = HashSet::new();
-        validators.insert(FOUNDER_ADDRESS.to_string());
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExecError::Tx { index, reason } => write!(f, "transaction {} invalid: {}", index, reason),
+            ExecError::Block(reason) => f.write_str(reason),
+        }
+    }
+}
 
-        let mut staked = HashMap::new();
-        staked.insert(FOUNDER_ADDRESS.to_string(), 10_000_000u64);
+/// Why a block could not be applied on top of the tip.
+enum BlockRejection {
+    /// Wrong height, parent, slot or timestamp, or a bad hash, signature or VRF proof.
+    Invalid,
+    Execution(ExecError),
+    StateRoot { claimed: String, computed: String },
+    /// Valid, but could not be written; the chain is left as it was.
+    Storage(String),
+}
 
-        let founder_secret = SecretKey::from_bytes(&[0u8; 64]).unwrap();
-        let founder_public = founder_secret.to_public();
+impl std::fmt::Display for BlockRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockRejection::Invalid => f.write_str("invalid header, signature or VRF proof"),
+            BlockRejection::Execution(e) => e.fmt(f),
+            BlockRejection::StateRoot { claimed, computed } => {
+                write!(f, "state root {} does not match computed {}", claimed, computed)
+            }
+            BlockRejection::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
 
-        let mut vrf_public_keys = HashMap::new();
-        vrf_public_keys.insert(FOUNDER_ADDRESS.to_string(), founder_public);
+enum ImportResult {
+    Applied,
+    Reorged,
+    SideChain,
+    Known,
+    Orphan,
+    Rejected,
+    /// Not kept, though not shown invalid: below the reorg window, or its
+    /// height already has enough competing blocks.
+    Ignored,
+    /// From a slot that has not started on our clock yet; the producer's
+    /// clock may just be ahead, so it can be offered again later.
+    Early,
+}
+
+/// Keys a node produces blocks with.
+struct Producer {
+    address: String,
+    vrf_secret: SecretKey,
+    signing_key: SigningKey,
+}
+
+struct MethaloxChain {
+    genesis_timestamp: u64,
+    slot_duration: u64,
+    genesis_hash: String,
+    /// Header of the best block; bodies are read from storage when needed.
+    tip: Block,
+    /// Recently dropped or failed transactions, oldest first in `tx_failure_order`.
+    tx_failures: HashMap<String, TxStatus>,
+    tx_failure_order: VecDeque<String>,
+    side_blocks: HashMap<String, Block>,
+    ledger: Ledger,
+    mempool: Mempool,
+    /// `None` when the chain is only replayed, as by `chain import`.
+    producer: Option<Producer>,
+    events: ChainEvents,
+    /// Blocks, receipts, undo records and the committed ledger. Every applied
+    /// or reverted block is written in one batch.
+    storage: Box<dyn ChainStorage>,
+    history: HistoryMode,
+    /// Lowest height whose body is stored.
+    body_floor: u64,
+    snapshots: SnapshotStore,
+}
+
+impl Transaction {
+    /// Length of the transaction's wire encoding, as counted against a block's byte limit.
+    fn encoded_size(&self) -> u64 {
+        bincode::serialized_size(self).expect("Transaction serializes")
+    }
+
+    /// Canonical bytes the sender signs, independent of any serialization format:
+    /// the domain tag, then each field in a fixed order. Strings and byte arrays are
+    /// a u32 big-endian length followed by the bytes, integers are u64 big-endian,
+    /// and the kind is a one-byte tag followed by its fields.
+    fn signing_bytes(&self) -> Vec<u8> {
+        fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+
+        let mut out = TX_SIGNING_DOMAIN.to_vec();
+        put_bytes(&mut out, self.from.as_bytes());
+        put_bytes(&mut out, self.to.as_bytes());
+        put_bytes(&mut out, self.asset.as_bytes());
+        out.extend_from_slice(&self.amount.to_be_bytes());
+        out.extend_from_slice(&self.nonce.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        put_bytes(&mut out, self.commitment.as_bytes());
+        out.extend_from_slice(&self.blinding_factor.to_be_bytes());
+        match &self.kind {
+            TransactionKind::Transfer => out.push(0),
+            TransactionKind::Stake { amount, vrf_pubkey, signing_pubkey } => {
+                out.push(1);
+                out.extend_from_slice(&amount.to_be_bytes());
+                put_bytes(&mut out, vrf_pubkey);
+                put_bytes(&mut out, signing_pubkey);
+            }
+            TransactionKind::Unstake { amount } => {
+                out.push(2);
+                out.extend_from_slice(&amount.to_be_bytes());
+            }
+            TransactionKind::Withdraw => out.push(3),
+        }
+        out
+    }
+
+    /// Checks the signature against the sender's key; needs no chain state.
+    fn verify_signature(&self) -> Result<(), String> {
+        let sig_bytes: [u8; 64] = self.signature.clone().try_into().map_err(|_| "Invalid signature length")?;
+        let signature = Signature::from_bytes(&sig_bytes);
+
+        let pubkey_bytes: [u8; 32] = hex::decode(&self.from)
+            .map_err(|_| "Invalid from address (hex)")?
+            .try_into()
+            .map_err(|_| "Invalid public key length")?;
+        let public_key = VerifyingKey::from_bytes(&pubkey_bytes).map_err(|_| "Invalid public key")?;
+
+        public_key.verify_strict(&self.signing_bytes(), &signature).map_err(|_| "Invalid signature".to_string())
+    }
+
+    /// Hex of `hash_tx`, the identifier returned by submission and used by the RPC.
+    fn hash(&self) -> String {
+        hex::encode(hash_tx(self))
+    }
+}
+
+/// SHA-256 of the signing bytes followed by the signature.
+fn hash_tx(tx: &Transaction) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(tx.signing_bytes());
+    hasher.update(&tx.signature);
+    hasher.finalize().into()
+}
+
+/// Binary Merkle tree over 32-byte leaves. An unpaired node moves up a level
+/// unchanged; hashing it with itself would give `[a, b, c]` and `[a, b, c, c]`
+/// the same root.
+fn merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return [0u8; 32];
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                [single] => *single,
+                _ => unreachable!("chunks(2) yields one or two nodes"),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+fn tx_root(transactions: &[Transaction]) -> String {
+    hex::encode(merkle_root(transactions.iter().map(hash_tx).collect()))
+}
+
+fn state_leaf<T: Serialize>(entry: &T) -> [u8; 32] {
+    Sha256::digest(bincode::serialize(entry).expect("State entry serializes")).into()
+}
+
+/// Validator block-signing key as carried in a Stake transaction. Small-order
+/// keys are refused: a signature under one can be forged without the secret.
+fn parse_signing_pubkey(bytes: &[u8]) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = bytes.try_into().map_err(|_| "Invalid signing public key length")?;
+    let key = VerifyingKey::from_bytes(&bytes).map_err(|_| "Invalid signing public key".to_string())?;
+    if key.is_weak() {
+        return Err("Weak signing public key".to_string());
+    }
+    Ok(key)
+}
+
+/// Validator VRF key. The identity point (all zero bytes) is refused, since
+/// anyone can produce a proof that verifies under it.
+fn parse_vrf_pubkey(bytes: &[u8]) -> Result<PublicKey, String> {
+    let key = PublicKey::from_bytes(bytes).map_err(|_| "Invalid VRF public key".to_string())?;
+    if key.to_bytes() == [0u8; 32] {
+        return Err("VRF public key is the identity point".to_string());
+    }
+    Ok(key)
+}
+
+/// VRF input for a slot: the parent hash followed by the slot number, so
+/// each slot re-rolls leadership on the same parent.
+fn vrf_transcript_bytes(prev_hash: &str, slot: u64) -> Vec<u8> {
+    let mut bytes = prev_hash.as_bytes().to_vec();
+    bytes.extend_from_slice(&slot.to_le_bytes());
+    bytes
+}
+
+/// Leader lottery value derived from a verified VRF input/output pair.
+fn vrf_leader_value(inout: &VRFInOut) -> u64 {
+    let bytes: [u8; 32] = inout.make_bytes(VRF_CONTEXT);
+    u64::from_le_bytes(bytes[0..8].try_into().unwrap())
+}
 
-        let mini_secret = MiniSecretKey::from_bytes(&node_secret_seed).unwrap();
-        let node_secret = mini_secret.expand(ExpansionMode::Ed25519);
-        let node_vrf_public = node_secret.to_public();
+fn restore_map<V: Clone>(map: &mut HashMap<String, V>, changes: &[(String, Option<V>)]) {
+    for (key, previous) in changes {
+        match previous {
+            Some(value) => {
+                map.insert(key.clone(), value.clone());
+            }
+            None => {
+                map.remove(key);
+            }
+        }
+    }
+}
+
+impl Ledger {
+    fn from_genesis(genesis: &GenesisSpec) -> Self {
+        let mut balances: HashMap<String, HashMap<String, (u64, u64)>> = HashMap::new();
+        // Staked XSX still circulates: Stake, Unstake and Withdraw only move it between balance, stake and unbonding
+        let mut xsx_circulating = 0u64;
+        for (addr, assets) in &genesis.allocations {
+            for (asset, amount) in assets {
+                balances.entry(addr.clone()).or_default().insert(asset.clone(), (*amount, 0));
+                if asset == "XSX" {
+                    xsx_circulating += amount;
+                }
+            }
+        }
+
+        let mut validators = HashSet::new();
+        let mut staked = HashMap::new();
+        let mut vrf_public_keys = HashMap::new();
+        let mut signing_keys = HashMap::new();
+        for v in &genesis.validators {
+            let vrf_public = GenesisSpec::parse_vrf_pubkey(&v.vrf_pubkey).expect("Genesis spec validated on load");
+            let signing_key = GenesisSpec::parse_signing_pubkey(&v.signing_pubkey).expect("Genesis spec validated on load");
+            validators.insert(v.address.clone());
+            *staked.entry(v.address.clone()).or_insert(0) += v.stake;
+            vrf_public_keys.insert(v.address.clone(), vrf_public);
+            signing_keys.insert(v.address.clone(), signing_key);
+            xsx_circulating += v.stake;
+        }
 
         Self {
-            blocks: vec![genesis],
             balances,
             treasury: HashMap::new(),
-            xsx_circulating: 21_000_000_000u64,
-            tx_pool: vec![],
+            xsx_circulating,
+            xsx_burned: 0,
             validators,
             staked,
+            unbonding: HashMap::new(),
+            unbonding_period: genesis.unbonding_period,
             vrf_public_keys,
-            node_address,
-            node_secret,
-            node_vrf_public,
+            signing_keys,
+            commitment: StateCommitment::default(),
+            journal: None,
         }
     }
 
-    fn from_state(state: MethaloxChainState, node_secret_seed: [u8; 32], node_address: String) -> Self {
-        let mini_secret = MiniSecretKey::from_bytes(&node_secret_seed).unwrap();
-        let node_secret = mini_secret.expand(ExpansionMode::Ed25519);
-        let node_vrf_public = node_secret.to_public();
+    /// Rebuilds the ledger from the balance and stake tables and the ledger record.
+    fn load(storage: &dyn ChainStorage) -> Result<Self, String> {
+        let record = storage.meta(META_LEDGER)?.ok_or("Stored chain has no ledger record")?;
+        let record = bincode::deserialize(&record).map_err(|e| format!("Corrupt ledger record in storage: {}", e))?;
+        Self::from_parts(storage.balances()?, storage.stakes()?, record)
+    }
 
+    fn from_parts(balance_entries: Vec<BalanceEntry>, stakes: Vec<(String, u64)>, record: LedgerRecord) -> Result<Self, String> {
+        let mut balances: HashMap<String, HashMap<String, (u64, u64)>> = HashMap::new();
+        for (addr, asset, value) in balance_entries {
+            balances.entry(addr).or_default().insert(asset, value);
+        }
         let mut vrf_public_keys = HashMap::new();
-        for (addr, bytes) in state.vrf_public_keys {
-            let pk = PublicKey::from_bytes(&bytes).expect("Invalid saved VRF public key");
+        for (addr, bytes) in record.vrf_public_keys {
+            let pk = PublicKey::from_bytes(&bytes).map_err(|_| format!("Invalid VRF public key for {}", addr))?;
             vrf_public_keys.insert(addr, pk);
         }
 
-        assert_eq!(node_vrf_public.to_bytes().to_vec(), state.node_vrf_public_bytes);
-
-        Self {
-            blocks: state.blocks,
-            balances: state.balances,
-            treasury: state.treasury,
-            xsx_circulating: state.xsx_circulating,
-            tx_pool: state.tx_pool,
-            validators: state.validators,
-            staked: state.staked,
+        Ok(Self {
+            balances,
+            treasury: record.treasury,
+            xsx_circulating: record.xsx_circulating,
+            xsx_burned: record.xsx_burned,
+            validators: record.validators,
+            staked: stakes.into_iter().collect(),
+            unbonding: record.unbonding,
+            unbonding_period: record.unbonding_period,
             vrf_public_keys,
-            node_address,
-            node_secret,
-            node_vrf_public,
-        }
+            signing_keys: record.signing_keys,
+            commitment: StateCommitment::default(),
+            journal: None,
+        })
     }
 
-    fn to_state(&self) -> MethaloxChainState {
-        let mut vrf_bytes = HashMap::new();
-        for (addr, pk) in &self.vrf_public_keys {
-            vrf_bytes.insert(addr.clone(), pk.to_bytes().to_vec());
-        }
-
-        MethaloxChainState {
-            blocks: self.blocks.clone(),
-            balances: self.balances.clone(),
+    fn to_record(&self) -> LedgerRecord {
+        LedgerRecord {
             treasury: self.treasury.clone(),
             xsx_circulating: self.xsx_circulating,
-            tx_pool: self.tx_pool.clone(),
+            xsx_burned: self.xsx_burned,
             validators: self.validators.clone(),
-            staked: self.staked.clone(),
-            vrf_public_keys: vrf_bytes,
-            node_vrf_public_bytes: self.node_vrf_public.to_bytes().to_vec(),
+            unbonding: self.unbonding.clone(),
+            unbonding_period: self.unbonding_period,
+            vrf_public_keys: self.vrf_public_keys.iter().map(|(addr, pk)| (addr.clone(), pk.to_bytes().to_vec())).collect(),
+            signing_keys: self.signing_keys.clone(),
         }
     }
 
-    fn hash_block(block: &Block) -> String {
-        let mut temp = block.clone();
-        temp.hash = String::new();
-        let serialized = bincode::serialize(&temp).unwrap();
-        hex::encode(Sha256::digest(&serialized))
+    /// Encoded `LedgerRecord`; balances and stakes are stored per entry instead.
+    fn record(&self) -> Vec<u8> {
+        bincode::serialize(&self.to_record()).expect("Ledger record serializes")
     }
 
-    fn validate_block(&self, block: &Block) -> bool {
-        let last_block = match self.blocks.last() {
-            Some(b) => b,
-            None => return false,
-        };
+    /// The whole ledger as a snapshot payload.
+    fn snapshot_payload(&self) -> Vec<u8> {
+        bincode::serialize(&self.snapshot()).expect("State snapshot serializes")
+    }
 
-        if block.index != last_block.index + 1 || block.prev_hash != last_block.hash {
-            return false;
-        }
+    fn snapshot(&self) -> StateSnapshot {
+        let mut balances: Vec<BalanceEntry> = self.balances
+            .iter()
+            .flat_map(|(addr, assets)| assets.iter().map(move |(asset, v)| (addr.clone(), asset.clone(), *v)))
+            .collect();
+        balances.sort();
+        let mut stakes: Vec<(String, u64)> = self.staked.iter().map(|(addr, v)| (addr.clone(), *v)).collect();
+        stakes.sort();
+        StateSnapshot { balances, stakes, ledger: self.to_record() }
+    }
 
-        if Self::hash_block(block) != block.hash {
-            return false;
+    fn from_snapshot(payload: &[u8]) -> Result<Self, String> {
+        let snapshot: StateSnapshot =
+            bincode::deserialize(payload).map_err(|e| format!("Cannot decode state snapshot: {}", e))?;
+        Self::from_parts(snapshot.balances, snapshot.stakes, snapshot.ledger)
+    }
+
+    fn validate_tx(&self, tx: &Transaction, height: u64) -> Result<(), String> {
+        if tx.nonce != self.next_nonce(&tx.from, &tx.asset) {
+            return Err("Invalid nonce".to_string());
         }
+        self.validate_tx_body(tx, height)
+    }
 
-        let ctx = signing_context(VRF_CONTEXT);
-        let transcript = ctx.bytes(&block.prev_hash.as_bytes());
+    fn next_nonce(&self, address: &str, asset: &str) -> u64 {
+        self.balances.get(address).and_then(|m| m.get(asset)).map(|(_, nonce)| nonce + 1).unwrap_or(1)
+    }
 
-        let Some(pubkey) = self.vrf_public_keys.get(&block.validator) else {
-            return false;
-        };
+    /// Every check but the nonce: signature, kind-specific rules and balance.
+    fn validate_tx_body(&self, tx: &Transaction, height: u64) -> Result<(), String> {
+        tx.verify_signature()?;
 
-        let vrf_output_bytes: [u8; 32] = match block.vrf_output.clone().try_into() {
-            Ok(arr) => arr,
-            Err(_) => return false,
-        };
+        let balance = self.balances.get(&tx.from).and_then(|m| m.get(&tx.asset)).map(|(b, _)| *b).unwrap_or(0);
 
-        let pre_output = match VRFOutput::from_bytes(&vrf_output_bytes) {
-            Ok(o) => o,
-            Err(_) => return false,
-        };
+        match &tx.kind {
+            TransactionKind::Transfer => {}
+            TransactionKind::Stake { amount, vrf_pubkey, signing_pubkey } => {
+                if tx.asset != "XSX" {
+                    return Err("Stake must be in XSX".to_string());
+                }
+                if *amount == 0 || *amount != tx.amount {
+                    return Err("Invalid stake amount".to_string());
+                }
+                let vrf_public = parse_vrf_pubkey(vrf_pubkey)?;
+                let taken = self.vrf_public_keys
+                    .iter()
+                    .any(|(addr, pk)| addr != &tx.from && pk == &vrf_public);
+                if taken {
+                    return Err("VRF public key already registered".to_string());
+                }
+                // Blocks are signed with the account key, so that is the only key a validator can register
+                parse_signing_pubkey(signing_pubkey)?;
+                if hex::encode(signing_pubkey) != tx.from {
+                    return Err("Signing public key must be the sender's account key".to_string());
+                }
+            }
+            TransactionKind::Unstake { amount } => {
+                if tx.asset != "XSX" {
+                    return Err("Unstake must be in XSX".to_string());
+                }
+                if *amount == 0 || *amount != tx.amount {
+                    return Err("Invalid unstake amount".to_string());
+                }
+                if self.staked.get(&tx.from).copied().unwrap_or(0) < *amount {
+                    return Err("Insufficient stake".to_string());
+                }
+            }
+            TransactionKind::Withdraw => {
+                if tx.asset != "XSX" {
+                    return Err("Withdraw must be in XSX".to_string());
+                }
+                if tx.amount != 0 {
+                    return Err("Withdraw amount must be zero".to_string());
+                }
+                if self.withdrawable(&tx.from, height) == 0 {
+                    return Err("Nothing to withdraw".to_string());
+                }
+            }
+        }
 
-        let proof = match VRFProof::from_bytes(&block.vrf_proof) {
-            Ok(p) => p,
-            Err(_) => return false,
-        };
+        let debit = Self::tx_debit(tx).ok_or("Amount overflows")?;
+        if balance < debit {
+            return Err("Insufficient balance".to_string());
+        }
+        if let TransactionKind::Transfer = tx.kind {
+            if tx.to != tx.from {
+                let to_balance = self.balances.get(&tx.to).and_then(|m| m.get(&tx.asset)).map(|(b, _)| *b).unwrap_or(0);
+                to_balance.checked_add(tx.amount).ok_or("Recipient balance overflows")?;
+            }
+        }
 
-        pubkey.vrf_verify(transcript, &pre_output, &proof).is_ok()
+        Ok(())
     }
 
-    fn validate_tx(&self, tx: &Transaction) -> Result<(), String> {
-        let mut tx_for_signing = tx.clone();
-        tx_for_signing.signature = vec![0u8; 64];
-        let message = bincode::serialize(&tx_for_signing).map_err(|_| "Serialization failed")?;
+    fn tx_fee(tx: &Transaction) -> u64 {
+        match tx.kind {
+            TransactionKind::Transfer => (tx.amount as u128 * TX_FEE_BPS as u128 / 10000) as u64,
+            TransactionKind::Stake { .. } | TransactionKind::Unstake { .. } | TransactionKind::Withdraw => 0,
+        }
+    }
 
-        let sig_bytes: [u8; 64] = tx.signature.clone().try_into().map_err(|_| "Invalid signature length")?;
-        let signature = Signature::from_bytes(&sig_bytes);
+    /// Amount taken from the sender's spendable balance, including the fee;
+    /// `None` if it does not fit in a u64.
+    fn tx_debit(tx: &Transaction) -> Option<u64> {
+        match tx.kind {
+            TransactionKind::Transfer | TransactionKind::Stake { .. } => tx.amount.checked_add(Self::tx_fee(tx)),
+            TransactionKind::Unstake { .. } | TransactionKind::Withdraw => Some(0),
+        }
+    }
 
-        let pubkey_bytes: [u8; 32] = hex::decode(&tx.from)
-            .map_err(|_| "Invalid from address (hex)")?
-            .try_into()
-            .map_err(|_| "Invalid public key length")?;
-        let public_key = VerifyingKey::from_bytes(&pubkey_bytes).map_err(|_| "Invalid public key")?;
+    fn withdrawable(&self, address: &str, height: u64) -> u64 {
+        self.unbonding
+            .get(address)
+            .map(|queue| {
+                queue.iter()
+                    .filter(|u| u.release_height <= height)
+                    .map(|u| u.amount)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    fn apply_tx(&mut self, tx: &Transaction, height: u64) {
+        let (balance, nonce) = self.balance_mut(&tx.from, &tx.asset);
+        *balance -= Self::tx_debit(tx).expect("Debit checked in validate_tx");
+        *nonce += 1;
 
-        public_key.verify(&message, &signature).map_err(|_| "Invalid signature")?;
+        if !matches!(tx.kind, TransactionKind::Transfer) {
+            self.touch_account(&tx.from);
+        }
+        match &tx.kind {
+            TransactionKind::Transfer => {
+                let (to_balance, _) = self.balance_mut(&tx.to, &tx.asset);
+                *to_balance += tx.amount;
+            }
+            TransactionKind::Stake { amount, vrf_pubkey, signing_pubkey } => {
+                // validate_tx has already checked the key bytes
+                let vrf_public = parse_vrf_pubkey(vrf_pubkey).expect("VRF key checked in validate_tx");
+                let signing_key = parse_signing_pubkey(signing_pubkey).expect("Signing key checked in validate_tx");
+                *self.staked.entry(tx.from.clone()).or_insert(0) += amount;
+                self.vrf_public_keys.insert(tx.from.clone(), vrf_public);
+                self.signing_keys.insert(tx.from.clone(), signing_key);
+                self.validators.insert(tx.from.clone());
+                info!("Staked {} XSX for validator {}", amount, tx.from);
+            }
+            TransactionKind::Unstake { amount } => {
+                let remaining = {
+                    let stake = self.staked.entry(tx.from.clone()).or_insert(0);
+                    *stake -= amount;
+                    *stake
+                };
+                if remaining == 0 {
+                    self.staked.remove(&tx.from);
+                    self.validators.remove(&tx.from);
+                    self.vrf_public_keys.remove(&tx.from);
+                    self.signing_keys.remove(&tx.from);
+                    info!("Validator {} fully unstaked", tx.from);
+                }
+                self.unbonding.entry(tx.from.clone()).or_default().push(Unbonding {
+                    amount: *amount,
+                    release_height: height + self.unbonding_period,
+                });
+                info!("Unbonding {} XSX for {} until block {}", amount, tx.from, height + self.unbonding_period);
+            }
+            TransactionKind::Withdraw => {
+                let released = self.withdrawable(&tx.from, height);
+                if let Some(queue) = self.unbonding.get_mut(&tx.from) {
+                    queue.retain(|u| u.release_height > height);
+                    if queue.is_empty() {
+                        self.unbonding.remove(&tx.from);
+                    }
+                }
+                let (balance, _) = self.balance_mut(&tx.from, "XSX");
+                *balance += released;
+                info!("Withdrew {} XSX of unbonded stake for {}", released, tx.from);
+            }
+        }
+    }
 
-        let (balance, expected_nonce) = self.balances
-            .get(&tx.from)
-            .and_then(|m| m.get(&tx.asset))
-            .copied()
-            .unwrap_or((0, 0));
+    /// Balance entry for writing, recorded as changed.
+    fn balance_mut(&mut self, address: &str, asset: &str) -> &mut (u64, u64) {
+        self.touch(StateKey::Balance(address.to_string(), asset.to_string()));
+        Self::get_balance_mut(&mut self.balances, address, asset)
+    }
 
-        if tx.nonce != expected_nonce + 1 {
-            return Err("Invalid nonce".to_string());
+    /// Records the staking entries of `address` as changed, before a
+    /// Stake, Unstake or Withdraw modifies them.
+    fn touch_account(&mut self, address: &str) {
+        for key in [StateKey::Staked, StateKey::Validator, StateKey::Unbonding, StateKey::VrfKey, StateKey::SigningKey] {
+            self.touch(key(address.to_string()));
         }
+    }
 
-        let fee = tx.amount * TX_FEE_BPS / 10000;
-        if balance < tx.amount + fee {
-            return Err("Insufficient balance".to_string());
+    /// Marks an entry for the next state root and, while a block is being
+    /// executed, saves its value the first time the block touches it.
+    fn touch(&mut self, key: StateKey) {
+        if let Some(journal) = self.journal.as_mut() {
+            match &key {
+                StateKey::Balance(addr, asset) => {
+                    journal.balances.entry((addr.clone(), asset.clone())).or_insert_with(|| {
+                        self.balances.get(addr).and_then(|m| m.get(asset)).copied()
+                    });
+                }
+                StateKey::Staked(addr) => {
+                    journal.staked.entry(addr.clone()).or_insert_with(|| self.staked.get(addr).copied());
+                }
+                StateKey::Validator(addr) => {
+                    journal.validators.entry(addr.clone()).or_insert_with(|| self.validators.contains(addr));
+                }
+                StateKey::Unbonding(addr) => {
+                    journal.unbonding.entry(addr.clone()).or_insert_with(|| self.unbonding.get(addr).cloned());
+                }
+                StateKey::VrfKey(addr) => {
+                    journal.vrf_public_keys.entry(addr.clone()).or_insert_with(|| self.vrf_public_keys.get(addr).cloned());
+                }
+                StateKey::SigningKey(addr) => {
+                    journal.signing_keys.entry(addr.clone()).or_insert_with(|| self.signing_keys.get(addr).copied());
+                }
+                // Nothing writes the treasury, and the supply counters are saved when the journal starts
+                StateKey::Treasury(_) | StateKey::Circulating | StateKey::Burned => {}
+            }
         }
+        self.commitment.dirty.insert(key);
+    }
 
-        Ok(())
+    /// Starts recording what the following changes overwrite.
+    fn begin_journal(&mut self) {
+        self.journal = Some(Journal {
+            balances: HashMap::new(),
+            staked: HashMap::new(),
+            validators: HashMap::new(),
+            unbonding: HashMap::new(),
+            vrf_public_keys: HashMap::new(),
+            signing_keys: HashMap::new(),
+            xsx_circulating: self.xsx_circulating,
+            xsx_burned: self.xsx_burned,
+        });
+    }
+
+    /// Stops recording and returns the undo record of the entries that
+    /// actually changed since `begin_journal`.
+    fn take_journal(&mut self) -> BlockUndo {
+        let journal = self.journal.take().expect("Journal started");
+        fn changed<V: Clone + PartialEq>(entries: HashMap<String, Option<V>>, current: &HashMap<String, V>) -> Vec<(String, Option<V>)> {
+            entries.into_iter().filter(|(key, prev)| current.get(key) != prev.as_ref()).collect()
+        }
+        let vrf_bytes = |pk: &PublicKey| pk.to_bytes().to_vec();
+        BlockUndo {
+            balances: journal.balances
+                .into_iter()
+                .filter(|((addr, asset), prev)| self.balances.get(addr).and_then(|m| m.get(asset)) != prev.as_ref())
+                .map(|((addr, asset), prev)| (addr, asset, prev))
+                .collect(),
+            treasury: Vec::new(),
+            staked: changed(journal.staked, &self.staked),
+            validators: journal.validators
+                .into_iter()
+                .filter(|(addr, was_validator)| self.validators.contains(addr) != *was_validator)
+                .collect(),
+            unbonding: changed(journal.unbonding, &self.unbonding),
+            vrf_public_keys: journal.vrf_public_keys
+                .into_iter()
+                .filter(|(addr, prev)| self.vrf_public_keys.get(addr).map(vrf_bytes) != prev.as_ref().map(vrf_bytes))
+                .map(|(addr, prev)| (addr, prev.as_ref().map(vrf_bytes)))
+                .collect(),
+            signing_keys: changed(journal.signing_keys, &self.signing_keys),
+            xsx_circulating: journal.xsx_circulating,
+            xsx_burned: journal.xsx_burned,
+        }
     }
 
     fn get_balance_mut<'a>(
@@ -305,6 +914,82 @@ impl MethaloxChain {
             .or_insert((0, 0))
     }
 
+    /// Applies every transaction of `block` in order, then fees and the tail
+    /// reward, and returns the undo record. Fails on the first invalid
+    /// transaction or if the fees the producer claimed differ from the
+    /// recomputed ones, leaving the ledger as it was.
+    fn execute_block(&mut self, block: &Block) -> Result<BlockUndo, ExecError> {
+        self.begin_journal();
+        let result = self.apply_block(block);
+        let undo = self.take_journal();
+        match result {
+            Ok(()) => Ok(undo),
+            Err(e) => {
+                self.revert(&undo);
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_block(&mut self, block: &Block) -> Result<(), ExecError> {
+        let mut fees: HashMap<String, u64> = HashMap::new();
+        for (index, tx) in block.transactions.iter().enumerate() {
+            self.validate_tx(tx, block.index)
+                .map_err(|reason| ExecError::Tx { index, reason })?;
+            let fee = Self::tx_fee(tx);
+            if fee > 0 {
+                *fees.entry(tx.asset.clone()).or_insert(0) += fee;
+            }
+            self.apply_tx(tx, block.index);
+        }
+
+        if fees != block.fees_collected {
+            return Err(ExecError::Block(format!(
+                "fees_collected {:?} does not match computed {:?}",
+                block.fees_collected, fees
+            )));
+        }
+
+        self.distribute_fees(&block.validator, &fees);
+        self.distribute_tail_reward();
+        Ok(())
+    }
+
+    /// A validator leads when its VRF value falls below its stake-weighted
+    /// share of the u64 range, so a validator holding half the stake wins
+    /// about half the draws.
+    fn is_leader(&self, validator: &str, vrf_value: u64) -> bool {
+        let total_stake = self.staked.values().sum::<u64>();
+        let stake = self.staked.get(validator).copied().unwrap_or(0);
+        if total_stake == 0 || stake == 0 {
+            return false;
+        }
+        let threshold = (u64::MAX / total_stake).saturating_mul(stake);
+        vrf_value < threshold
+    }
+
+    fn distribute_fees(&mut self, validator: &str, fees: &HashMap<String, u64>) {
+        for (asset, total_fee) in fees {
+            let validator_share = total_fee / 2;
+            let founder_rake = total_fee - validator_share;
+
+            let (val_balance, _) = self.balance_mut(validator, asset);
+            *val_balance += validator_share;
+
+            if asset == "XSX" {
+                let burn_amount = (founder_rake as f64 * XSX_BURN_RATE) as u64;
+                let founder_keep = founder_rake - burn_amount;
+                debug!("Burned {} XSX from founder rake", burn_amount);
+                self.xsx_burned += burn_amount;
+                let (founder_balance, _) = self.balance_mut(FOUNDER_ADDRESS, asset);
+                *founder_balance += founder_keep;
+            } else {
+                let (founder_balance, _) = self.balance_mut(FOUNDER_ADDRESS, asset);
+                *founder_balance += founder_rake;
+            }
+        }
+    }
+
     fn distribute_tail_reward(&mut self) {
         let total_stake: u64 = self.staked.values().sum();
         if total_stake == 0 {
@@ -319,221 +1004,1391 @@ impl MethaloxChain {
             return;
         }
 
-        for (addr, stake) in &self.staked {
-            let share = (tail_reward_total * *stake) / total_stake;
-            if share > 0 {
-                let (balance, _) = Self::get_balance_mut(&mut self.balances, addr, "XSX");
-                *balance += share;
+        let shares: Vec<(String, u64)> = self.staked
+            .iter()
+            .map(|(addr, stake)| (addr.clone(), (tail_reward_total * *stake) / total_stake))
+            .filter(|(_, share)| *share > 0)
+            .collect();
+        // Rounding dust is not minted, so circulating XSX matches what was paid out
+        let mut minted = 0;
+        for (addr, share) in shares {
+            let (balance, _) = self.balance_mut(&addr, "XSX");
+            *balance += share;
+            minted += share;
+        }
+
+        self.xsx_circulating += minted;
+    }
+
+    /// Root of the state tree over every ledger entry, keyed by the hash of
+    /// the entry's `StateKey`. Only entries changed since the last call are
+    /// rehashed.
+    fn state_root(&mut self) -> String {
+        if !self.commitment.built {
+            let keys = self.state_keys();
+            self.commitment.dirty.extend(keys);
+            self.commitment.built = true;
+        }
+        self.commitment.dirty.insert(StateKey::Circulating);
+        self.commitment.dirty.insert(StateKey::Burned);
+        let updates: Vec<([u8; 32], Option<[u8; 32]>)> = std::mem::take(&mut self.commitment.dirty)
+            .iter()
+            .map(|key| (state_leaf(key), self.state_entry(key)))
+            .collect();
+        for (key, leaf) in updates {
+            self.commitment.tree.set(key, leaf);
+        }
+        hex::encode(self.commitment.tree.root())
+    }
+
+    /// Every entry currently in the ledger.
+    fn state_keys(&self) -> Vec<StateKey> {
+        let mut keys: Vec<StateKey> = self.balances
+            .iter()
+            .flat_map(|(addr, assets)| assets.keys().map(move |asset| StateKey::Balance(addr.clone(), asset.clone())))
+            .collect();
+        keys.extend(self.treasury.keys().map(|addr| StateKey::Treasury(addr.clone())));
+        keys.extend(self.staked.keys().map(|addr| StateKey::Staked(addr.clone())));
+        keys.extend(self.validators.iter().map(|addr| StateKey::Validator(addr.clone())));
+        keys.extend(self.unbonding.keys().map(|addr| StateKey::Unbonding(addr.clone())));
+        keys.extend(self.vrf_public_keys.keys().map(|addr| StateKey::VrfKey(addr.clone())));
+        keys.extend(self.signing_keys.keys().map(|addr| StateKey::SigningKey(addr.clone())));
+        keys
+    }
+
+    /// Leaf hash of an entry, or `None` if the ledger does not hold it.
+    fn state_entry(&self, key: &StateKey) -> Option<[u8; 32]> {
+        match key {
+            StateKey::Balance(addr, asset) => {
+                self.balances.get(addr).and_then(|m| m.get(asset)).map(|v| state_leaf(&("balance", addr, asset, v)))
+            }
+            StateKey::Treasury(addr) => self.treasury.get(addr).map(|v| state_leaf(&("treasury", addr, v))),
+            StateKey::Staked(addr) => self.staked.get(addr).map(|v| state_leaf(&("staked", addr, v))),
+            StateKey::Validator(addr) => self.validators.contains(addr).then(|| state_leaf(&("validator", addr))),
+            StateKey::Unbonding(addr) => self.unbonding.get(addr).map(|q| state_leaf(&("unbonding", addr, q))),
+            StateKey::VrfKey(addr) => {
+                self.vrf_public_keys.get(addr).map(|pk| state_leaf(&("vrf_public_key", addr, pk.to_bytes())))
+            }
+            StateKey::SigningKey(addr) => {
+                self.signing_keys.get(addr).map(|key| state_leaf(&("signing_key", addr, key.to_bytes())))
             }
+            StateKey::Circulating => Some(state_leaf(&("xsx_circulating", self.xsx_circulating))),
+            StateKey::Burned => Some(state_leaf(&("xsx_burned", self.xsx_burned))),
         }
+    }
 
-        self.xsx_circulating += tail_reward_total;
+    /// Undo record that puts back the current values of every entry `undo`
+    /// lists, so applying `undo` can itself be reverted.
+    fn capture(&self, undo: &BlockUndo) -> BlockUndo {
+        let current = |keys: &[(String, Option<u64>)], map: &HashMap<String, u64>| -> Vec<(String, Option<u64>)> {
+            keys.iter().map(|(addr, _)| (addr.clone(), map.get(addr).copied())).collect()
+        };
+        BlockUndo {
+            balances: undo.balances
+                .iter()
+                .map(|(addr, asset, _)| {
+                    (addr.clone(), asset.clone(), self.balances.get(addr).and_then(|m| m.get(asset)).copied())
+                })
+                .collect(),
+            treasury: current(&undo.treasury, &self.treasury),
+            staked: current(&undo.staked, &self.staked),
+            validators: undo.validators.iter().map(|(addr, _)| (addr.clone(), self.validators.contains(addr))).collect(),
+            unbonding: undo.unbonding.iter().map(|(addr, _)| (addr.clone(), self.unbonding.get(addr).cloned())).collect(),
+            vrf_public_keys: undo.vrf_public_keys
+                .iter()
+                .map(|(addr, _)| (addr.clone(), self.vrf_public_keys.get(addr).map(|pk| pk.to_bytes().to_vec())))
+                .collect(),
+            signing_keys: undo.signing_keys.iter().map(|(addr, _)| (addr.clone(), self.signing_keys.get(addr).copied())).collect(),
+            xsx_circulating: self.xsx_circulating,
+            xsx_burned: self.xsx_burned,
+        }
     }
 
-    fn create_block_if_leader(&mut self) -> Option<Vec<u8>> {
-        let last_block = match self.blocks.last() {
-            Some(b) => b,
-            None => return None,
+    fn revert(&mut self, undo: &BlockUndo) {
+        for (addr, asset, _) in &undo.balances {
+            self.touch(StateKey::Balance(addr.clone(), asset.clone()));
+        }
+        for (addr, _) in &undo.treasury {
+            self.touch(StateKey::Treasury(addr.clone()));
+        }
+        let accounts: BTreeSet<&String> = undo.staked
+            .iter()
+            .map(|(addr, _)| addr)
+            .chain(undo.validators.iter().map(|(addr, _)| addr))
+            .chain(undo.unbonding.iter().map(|(addr, _)| addr))
+            .chain(undo.vrf_public_keys.iter().map(|(addr, _)| addr))
+            .chain(undo.signing_keys.iter().map(|(addr, _)| addr))
+            .collect();
+        for addr in accounts {
+            self.touch_account(addr);
+        }
+
+        for (addr, asset, previous) in &undo.balances {
+            match previous {
+                Some(value) => {
+                    *Self::get_balance_mut(&mut self.balances, addr, asset) = *value;
+                }
+                None => {
+                    if let Some(assets) = self.balances.get_mut(addr) {
+                        assets.remove(asset);
+                        if assets.is_empty() {
+                            self.balances.remove(addr);
+                        }
+                    }
+                }
+            }
+        }
+
+        restore_map(&mut self.treasury, &undo.treasury);
+        restore_map(&mut self.staked, &undo.staked);
+        restore_map(&mut self.unbonding, &undo.unbonding);
+        restore_map(&mut self.signing_keys, &undo.signing_keys);
+
+        for (addr, was_validator) in &undo.validators {
+            if *was_validator {
+                self.validators.insert(addr.clone());
+            } else {
+                self.validators.remove(addr);
+            }
+        }
+
+        for (addr, previous) in &undo.vrf_public_keys {
+            match previous {
+                Some(bytes) => {
+                    let pk = PublicKey::from_bytes(bytes).expect("Undo record holds a valid VRF key");
+                    self.vrf_public_keys.insert(addr.clone(), pk);
+                }
+                None => {
+                    self.vrf_public_keys.remove(addr);
+                }
+            }
+        }
+
+        self.xsx_circulating = undo.xsx_circulating;
+        self.xsx_burned = undo.xsx_burned;
+    }
+}
+
+impl MethaloxChain {
+    fn genesis_block(genesis: &GenesisSpec) -> Block {
+        let mut block = Block {
+            index: 0,
+            slot: 0,
+            timestamp: genesis.timestamp,
+            transactions: vec![],
+            prev_hash: genesis.spec_hash(),
+            hash: String::new(),
+            validator: FOUNDER_ADDRESS.to_string(),
+            fees_collected: HashMap::new(),
+            vrf_proof: vec![],
+            vrf_output: vec![],
+            signature: vec![],
+            tx_root: tx_root(&[]),
+            state_root: Ledger::from_genesis(genesis).state_root(),
         };
+        block.hash = Self::hash_block(&block);
+        block
+    }
 
-        let ctx = signing_context(VRF_CONTEXT);
-        let transcript = ctx.bytes(&last_block.hash.as_bytes());
+    /// Opens the chain held by `storage`, first writing the genesis block and
+    /// state if it is empty. Without `keys` the chain never produces blocks,
+    /// and the store is not bound to a node's VRF key.
+    fn open(
+        mut storage: Box<dyn ChainStorage>,
+        history: HistoryMode,
+        snapshots: SnapshotStore,
+        keys: Option<&NodeKeys>,
+        genesis: &GenesisSpec,
+    ) -> Result<Self, String> {
+        let genesis_hash = Self::genesis_block(genesis).hash;
+
+        if storage.tip()?.is_none() {
+            storage.commit(Self::initial_batch(&[Self::genesis_block(genesis)], &Ledger::from_genesis(genesis), &[]))?;
+        }
 
-        let keypair = Keypair::from(self.node_secret.clone());
-        let (inout, proof, _) = keypair.vrf_sign(transcript.clone());
+        let saved_genesis = storage.header(0)?.map(|b| b.hash).unwrap_or_default();
+        if saved_genesis != genesis_hash {
+            return Err(format!(
+                "Stored chain was created from genesis {} but the configured genesis is {}",
+                saved_genesis, genesis_hash
+            ));
+        }
+        if let Some(keys) = keys {
+            let node_vrf_bytes = keys.vrf_secret.to_public().to_bytes().to_vec();
+            match storage.meta(META_NODE_VRF_KEY)? {
+                Some(saved) if saved != node_vrf_bytes => {
+                    return Err("Stored chain belongs to a node with a different VRF key".to_string());
+                }
+                Some(_) => {}
+                // Created without keys, e.g. by `chain import`: the first node to run on it claims it
+                None => {
+                    let mut batch = WriteBatch::default();
+                    batch.put_meta(META_NODE_VRF_KEY, node_vrf_bytes);
+                    storage.commit(batch)?;
+                }
+            }
+        }
 
-        let vrf_output_bytes: [u8; 32] = inout.make_bytes(&[]);
+        let height = storage.tip()?.unwrap_or(0);
+        let tip = storage.header(height)?.ok_or_else(|| format!("Stored chain is missing header #{}", height))?;
+        let ledger = Ledger::load(storage.as_ref())?;
+        let pool: Vec<Transaction> = match storage.meta(META_MEMPOOL)? {
+            Some(bytes) => bincode::deserialize(&bytes).map_err(|e| format!("Corrupt saved mempool: {}", e))?,
+            None => Vec::new(),
+        };
+        // Missing in databases from before pruning and fast sync, which hold every body
+        let body_floor = match storage.meta(META_BODY_FLOOR)? {
+            Some(bytes) => bincode::deserialize(&bytes).map_err(|e| format!("Corrupt body floor record: {}", e))?,
+            None => 0,
+        };
 
-        let vrf_hash: [u8; 32] = vrf_output_bytes;
+        let mut chain = Self {
+            genesis_timestamp: genesis.timestamp,
+            slot_duration: genesis.slot_duration,
+            genesis_hash,
+            tip,
+            tx_failures: HashMap::new(),
+            tx_failure_order: VecDeque::new(),
+            side_blocks: HashMap::new(),
+            ledger,
+            mempool: Mempool::default(),
+            producer: keys.map(|keys| Producer {
+                address: keys.address(),
+                vrf_secret: keys.vrf_secret.clone(),
+                signing_key: keys.account.clone(),
+            }),
+            events: ChainEvents::new(height.saturating_sub(MAX_REORG_DEPTH)),
+            storage,
+            history,
+            body_floor,
+            snapshots,
+        };
+        chain.prepare_history(genesis)?;
+        for tx in pool {
+            if let Err(e) = chain.submit_transaction(tx) {
+                debug!("Dropping saved pool transaction: {}", e);
+            }
+        }
+        Ok(chain)
+    }
 
-        let total_stake = self.staked.values().sum::<u64>();
-        if total_stake == 0 {
-            return None;
+    /// Brings the stored history in line with the configured mode: prunes
+    /// bodies a pruned node no longer keeps, builds the balance history an
+    /// archive node lacks, and drops it when a node leaves archive mode.
+    fn prepare_history(&mut self, genesis: &GenesisSpec) -> Result<(), String> {
+        let archived = self.storage.meta(META_ARCHIVE)?.is_some();
+        match self.history {
+            HistoryMode::Archive if self.body_floor > 0 => {
+                return Err(format!(
+                    "Archive mode needs every block since genesis, but bodies below #{} are not stored; \
+                     start from an empty data directory",
+                    self.body_floor
+                ));
+            }
+            HistoryMode::Archive if !archived => self.build_balance_history(genesis)?,
+            HistoryMode::Archive => {}
+            HistoryMode::Full | HistoryMode::Pruned { .. } if archived => {
+                info!("Not an archive node any more — dropping the balance history");
+                let mut batch = WriteBatch::default();
+                batch.clear_balance_history();
+                batch.delete_meta(META_ARCHIVE);
+                self.storage.commit(batch)?;
+            }
+            HistoryMode::Full | HistoryMode::Pruned { .. } => {}
         }
 
-        let my_stake = self.staked.get(&self.node_address).copied().unwrap_or(0);
-        let threshold = u64::MAX - (u64::MAX / total_stake) * my_stake;
-        let vrf_value = u64::from_le_bytes(vrf_hash[0..8].try_into().unwrap());
+        let floor = self.body_floor;
+        self.prune_bodies()?;
+        if self.body_floor > floor + 1 {
+            info!("Pruned the bodies of blocks #{}..#{}", floor, self.body_floor - 1);
+        }
+        Ok(())
+    }
 
-        if vrf_value > threshold {
-            return None;
+    /// Re-executes every stored block from genesis to record each balance as
+    /// it stood after every block.
+    fn build_balance_history(&mut self, genesis: &GenesisSpec) -> Result<(), String> {
+        let height = self.height();
+        if height > 0 {
+            info!("Building the balance history of {} block(s) for archive mode...", height);
+        }
+        let mut ledger = Ledger::from_genesis(genesis);
+        let mut batch = WriteBatch::default();
+        for (addr, assets) in &ledger.balances {
+            for (asset, value) in assets {
+                batch.put_balance_history(addr, asset, 0, Some(*value));
+            }
+        }
+        for index in 1..=height {
+            let block = self.block(index).ok_or_else(|| format!("Block #{} is missing from storage", index))?;
+            let undo = ledger.execute_block(&block).map_err(|e| format!("Block #{} does not re-execute: {}", index, e))?;
+            Self::stage_balance_history(&mut batch, &ledger, index, &undo);
+            if index % HISTORY_BATCH_BLOCKS == 0 {
+                self.storage.commit(std::mem::take(&mut batch))?;
+            }
+        }
+        if ledger.state_root() != self.tip.state_root {
+            return Err(format!("Re-executed state does not match the state root of block #{}", height));
         }
+        batch.put_meta(META_ARCHIVE, Vec::new());
+        self.storage.commit(batch)
+    }
 
-        let tx_pool_snapshot = self.tx_pool.clone();
-        self.tx_pool.clear();
+    /// Records the balances `undo` lists as changed, at their values in `ledger`.
+    fn stage_balance_history(batch: &mut WriteBatch, ledger: &Ledger, height: u64, undo: &BlockUndo) {
+        for (addr, asset, _) in &undo.balances {
+            let value = ledger.balances.get(addr).and_then(|m| m.get(asset)).copied();
+            batch.put_balance_history(addr, asset, height, value);
+        }
+    }
 
-        let mut valid_txs = Vec::new();
-        for tx in tx_pool_snapshot {
-            if self.validate_tx(&tx).is_ok() {
-                valid_txs.push(tx);
-            } else {
-                println!("Dropped invalid tx from pool");
+    /// Drops the bodies and receipts of blocks older than a pruned node keeps.
+    fn prune_bodies(&mut self) -> Result<(), String> {
+        let HistoryMode::Pruned { keep_blocks } = self.history else {
+            return Ok(());
+        };
+        let target = (self.height() + 1).saturating_sub(keep_blocks);
+        while self.body_floor < target {
+            let end = target.min(self.body_floor + HISTORY_BATCH_BLOCKS);
+            let mut batch = WriteBatch::default();
+            for index in self.body_floor..end {
+                for tx in self.block(index).map(|b| b.transactions).unwrap_or_default() {
+                    batch.delete_receipt(tx.hash());
+                }
+                batch.prune_body(index);
             }
+            batch.put_meta(META_BODY_FLOOR, bincode::serialize(&end).expect("Height serializes"));
+            self.storage.commit(batch)?;
+            self.body_floor = end;
         }
+        Ok(())
+    }
 
-        let mut fees_this_block = HashMap::new();
-        for tx in &valid_txs {
-            if !matches!(tx.kind, TransactionKind::Stake { .. }) {
-                let fee = tx.amount * TX_FEE_BPS / 10000;
-                *fees_this_block.entry(tx.asset.clone()).or_insert(0) += fee;
+    /// Writes a whole chain into empty storage. `undo` holds the records of
+    /// the last `undo.len()` blocks.
+    fn initial_batch(blocks: &[Block], ledger: &Ledger, undo: &[BlockUndo]) -> WriteBatch {
+        let mut batch = WriteBatch::default();
+        for block in blocks {
+            batch.put_block(block);
+            for (hash, receipt) in Self::block_receipts(block) {
+                batch.put_receipt(hash, receipt);
+            }
+        }
+        let first_undo = blocks.len().saturating_sub(undo.len()) as u64;
+        for (offset, record) in undo.iter().enumerate() {
+            batch.put_undo(first_undo + offset as u64, record.clone());
+        }
+        for (addr, assets) in &ledger.balances {
+            for (asset, value) in assets {
+                batch.put_balance(addr, asset, Some(*value));
             }
+        }
+        for (addr, stake) in &ledger.staked {
+            batch.put_stake(addr, Some(*stake));
+        }
+        batch.put_meta(META_LEDGER, ledger.record());
+        batch.put_meta(META_BODY_FLOOR, bincode::serialize(&0u64).expect("Height serializes"));
+        batch.put_meta(META_SCHEMA_VERSION, migration::encode_version(migration::SCHEMA_VERSION));
+        batch
+    }
 
-            let fee = tx.amount * TX_FEE_BPS / 10000;
-            let (balance, _) = Self::get_balance_mut(&mut self.balances, &tx.from, &tx.asset);
-            *balance -= tx.amount + fee;
+    /// Writes a state loaded from an old checkpoint file into empty storage
+    /// and returns its pending transactions.
+    fn import_state(storage: &mut dyn ChainStorage, state: MethaloxChainState) -> Result<Vec<Transaction>, String> {
+        let mut vrf_public_keys = HashMap::new();
+        for (addr, bytes) in state.vrf_public_keys {
+            let pk = PublicKey::from_bytes(&bytes).map_err(|_| format!("Invalid saved VRF public key for {}", addr))?;
+            vrf_public_keys.insert(addr, pk);
+        }
+        let ledger = Ledger {
+            balances: state.balances,
+            treasury: state.treasury,
+            xsx_circulating: state.xsx_circulating,
+            xsx_burned: state.xsx_burned,
+            validators: state.validators,
+            staked: state.staked,
+            unbonding: state.unbonding,
+            unbonding_period: state.unbonding_period,
+            vrf_public_keys,
+            signing_keys: state.signing_keys,
+            commitment: StateCommitment::default(),
+            journal: None,
+        };
 
-            let (to_balance, _) = Self::get_balance_mut(&mut self.balances, &tx.to, &tx.asset);
-            *to_balance += tx.amount;
+        let mut batch = Self::initial_batch(&state.blocks, &ledger, &state.undo);
+        batch.put_meta(META_NODE_VRF_KEY, state.node_vrf_public_bytes);
+        storage.commit(batch)?;
+        Ok(state.tx_pool)
+    }
 
-            let (_, nonce) = Self::get_balance_mut(&mut self.balances, &tx.from, &tx.asset);
-            *nonce += 1;
+    fn hash_block(block: &Block) -> String {
+        let mut temp = block.clone();
+        temp.hash = String::new();
+        temp.signature = vec![];
+        let serialized = bincode::serialize(&temp).unwrap();
+        hex::encode(Sha256::digest(&serialized))
+    }
+
+    /// First eight bytes of the VRF output; the fork-choice tie breaker (lower wins).
+    fn vrf_value(block: &Block) -> u64 {
+        block.vrf_output
+            .get(0..8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .unwrap_or(u64::MAX)
+    }
+
+    fn validate_block(&self, block: &Block) -> bool {
+        let last_block = &self.tip;
+
+        if block.index != last_block.index + 1 || block.prev_hash != last_block.hash {
+            return false;
         }
 
+        self.validate_header(block, last_block, &self.ledger)
+    }
+
+    /// Everything `validate_block` checks except the link to the tip: timing
+    /// after `parent`, hashes, a single copy of each transaction, and the
+    /// producer's signature and VRF leader proof under the keys and stake in
+    /// `ledger`.
+    fn validate_header(&self, block: &Block, parent: &Block, ledger: &Ledger) -> bool {
+        if block.slot <= parent.slot || block.slot > self.current_slot() {
+            return false;
+        }
+
+        if block.timestamp != self.slot_start(block.slot) {
+            return false;
+        }
+
+        if Self::hash_block(block) != block.hash || tx_root(&block.transactions) != block.tx_root {
+            return false;
+        }
+
+        let mut seen = HashSet::new();
+        if !block.transactions.iter().all(|tx| seen.insert(hash_tx(tx))) {
+            return false;
+        }
+
+        if block.transactions.len() > MAX_BLOCK_TXS
+            || block.transactions.iter().map(Transaction::encoded_size).sum::<u64>() > MAX_BLOCK_TX_BYTES
+        {
+            return false;
+        }
+
+        // The hash covers the whole body, so this signature binds it to the producer
+        let Some(signing_key) = ledger.signing_keys.get(&block.validator) else {
+            return false;
+        };
+        let Ok(sig_bytes) = <[u8; 64]>::try_from(block.signature.as_slice()) else {
+            return false;
+        };
+        if signing_key.verify_strict(block.hash.as_bytes(), &Signature::from_bytes(&sig_bytes)).is_err() {
+            return false;
+        }
+
+        let ctx = signing_context(VRF_CONTEXT);
+        let transcript = ctx.bytes(&vrf_transcript_bytes(&block.prev_hash, block.slot));
+
+        let Some(pubkey) = ledger.vrf_public_keys.get(&block.validator) else {
+            return false;
+        };
+
+        let vrf_output_bytes: [u8; 32] = match block.vrf_output.clone().try_into() {
+            Ok(arr) => arr,
+            Err(_) => return false,
+        };
+
+        let pre_output = match VRFOutput::from_bytes(&vrf_output_bytes) {
+            Ok(o) => o,
+            Err(_) => return false,
+        };
+
+        let proof = match VRFProof::from_bytes(&block.vrf_proof) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+
+        let Ok((inout, _)) = pubkey.vrf_verify(transcript, &pre_output, &proof) else {
+            return false;
+        };
+
+        // Checked against the parent state's stake, which is what the producer saw
+        ledger.is_leader(&block.validator, vrf_leader_value(&inout))
+    }
+
+    /// Slot containing the current wall-clock time; 0 before genesis.
+    fn current_slot(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        now.saturating_sub(self.genesis_timestamp) / self.slot_duration
+    }
+
+    fn slot_start(&self, slot: u64) -> u64 {
+        self.genesis_timestamp + slot * self.slot_duration
+    }
+
+    fn next_height(&self) -> u64 {
+        self.tip.index + 1
+    }
+
+    fn create_block_if_leader(&mut self) -> Option<Vec<u8>> {
+        let last_block = self.tip.clone();
+
+        // One attempt per slot: nothing to do until the slot after the tip's
+        let slot = self.current_slot();
+        if slot <= last_block.slot {
+            return None;
+        }
+
+        let producer = self.producer.as_ref()?;
+        let ctx = signing_context(VRF_CONTEXT);
+        let transcript = ctx.bytes(&vrf_transcript_bytes(&last_block.hash, slot));
+
+        let keypair = Keypair::from(producer.vrf_secret.clone());
+        let (inout, proof, _) = keypair.vrf_sign(transcript.clone());
+
+        if !self.ledger.is_leader(&producer.address, vrf_leader_value(&inout)) {
+            return None;
+        }
+
+        // Transactions are applied in place under a journal, then rolled back once the state root is
+        // known so validate_block sees the parent state. They stay in the mempool until the block is committed.
+        let ledger = &mut self.ledger;
+        ledger.begin_journal();
+        let mut fees_this_block = HashMap::new();
+        let valid_txs = self.mempool.select(MAX_BLOCK_TXS, MAX_BLOCK_TX_BYTES, |tx| match ledger.validate_tx(tx, last_block.index + 1) {
+            Ok(()) => {
+                let fee = Ledger::tx_fee(tx);
+                if fee > 0 {
+                    *fees_this_block.entry(tx.asset.clone()).or_insert(0) += fee;
+                }
+                ledger.apply_tx(tx, last_block.index + 1);
+                true
+            }
+            Err(reason) => {
+                debug!("Holding back tx {} and later nonces: {}", tx.hash(), reason);
+                false
+            }
+        });
+
+        ledger.distribute_fees(&producer.address, &fees_this_block);
+        ledger.distribute_tail_reward();
+        let state_root = ledger.state_root();
+        let undo = ledger.take_journal();
+        let redo = ledger.capture(&undo);
+        ledger.revert(&undo);
+
         let mut new_block = Block {
             index: last_block.index + 1,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs(),
-            transactions: valid_txs,
+            slot,
+            timestamp: self.slot_start(slot),
+            transactions: valid_txs.clone(),
             prev_hash: last_block.hash.clone(),
             hash: String::new(),
-            validator: self.node_address.clone(),
+            validator: producer.address.clone(),
             fees_collected: fees_this_block.clone(),
             vrf_proof: proof.to_bytes().to_vec(),
-            vrf_output: vrf_output_bytes.to_vec(),
+            vrf_output: inout.to_output().to_bytes().to_vec(),
+            signature: vec![],
+            tx_root: tx_root(&valid_txs),
+            state_root,
         };
 
         new_block.hash = Self::hash_block(&new_block);
+        new_block.signature = producer.signing_key.sign(new_block.hash.as_bytes()).to_bytes().to_vec();
 
-        if self.validate_block(&new_block) {
-            println!("BLOCK PRODUCED #{} by {}", new_block.index, new_block.validator);
-            self.blocks.push(new_block.clone());
+        if !self.validate_block(&new_block) {
+            return None;
+        }
+        self.ledger.revert(&redo);
+        if let Err(e) = self.push_block(new_block.clone(), undo) {
+            error!("Cannot store produced block #{}: {}", new_block.index, e);
+            return None;
+        }
+        info!("BLOCK PRODUCED #{} by {}", new_block.index, new_block.validator);
+        bincode::serialize(&new_block).ok()
+    }
 
-            for (asset, total_fee) in fees_this_block {
-                let validator_share = total_fee / 2;
-                let founder_rake = total_fee - validator_share;
+    fn height(&self) -> u64 {
+        self.tip.index
+    }
 
-                let (val_balance, _) = Self::get_balance_mut(&mut self.balances, &self.node_address, &asset);
-                *val_balance += validator_share;
+    fn best_hash(&self) -> String {
+        self.tip.hash.clone()
+    }
 
-                if asset == "XSX" {
-                    let burn_amount = (founder_rake as f64 * XSX_BURN_RATE) as u64;
-                    let founder_keep = founder_rake - burn_amount;
-                    println!("Burned {} XSX from founder rake", burn_amount);
-                    let (founder_balance, _) = Self::get_balance_mut(&mut self.balances, FOUNDER_ADDRESS, &asset);
-                    *founder_balance += founder_keep;
-                } else {
-                    let (founder_balance, _) = Self::get_balance_mut(&mut self.balances, FOUNDER_ADDRESS, &asset);
-                    *founder_balance += founder_rake;
+    fn genesis_hash(&self) -> String {
+        self.genesis_hash.clone()
+    }
+
+    /// Canonical block at `index`, read from storage.
+    fn block(&self, index: u64) -> Option<Block> {
+        stored(self.storage.block(index))
+    }
+
+    fn header(&self, index: u64) -> Option<Block> {
+        stored(self.storage.header(index))
+    }
+
+    /// Height of a block on the best chain.
+    fn block_height(&self, hash: &str) -> Option<u64> {
+        stored(self.storage.block_height(hash))
+    }
+
+    fn receipt(&self, tx_hash: &str) -> Option<Receipt> {
+        stored(self.storage.receipt(tx_hash))
+    }
+
+    /// Balance and nonce of an account after the block at `height`. Archive nodes only.
+    fn balance_at(&self, address: &str, asset: &str, height: u64) -> Result<(u64, u64), String> {
+        if self.history != HistoryMode::Archive {
+            return Err("Historical balances are only kept by archive nodes (--history archive)".to_string());
+        }
+        if height > self.height() {
+            return Err(format!("Block #{} is above the chain tip #{}", height, self.height()));
+        }
+        Ok(self.storage.balance_at(address, asset, height)?.unwrap_or((0, 0)))
+    }
+
+    /// Canonical blocks from `start`, stopping after `count` blocks or before
+    /// their encoding passes `max_bytes`; the first block is always included.
+    fn blocks_range(&self, start: u64, count: u64, max_bytes: u64) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut bytes = 0;
+        for index in start..start.saturating_add(count).min(self.next_height()) {
+            let Some(block) = self.block(index) else {
+                break;
+            };
+            bytes += bincode::serialized_size(&block).expect("Block serializes");
+            if bytes > max_bytes && !blocks.is_empty() {
+                break;
+            }
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn block_by_hash(&self, hash: &str) -> Option<Block> {
+        match self.block_height(hash) {
+            Some(index) => self.block(index),
+            None => self.side_blocks.get(hash).cloned(),
+        }
+    }
+
+    /// Looks a transaction up by hash in the canonical chain, returning it with its block.
+    fn transaction_by_hash(&self, hash: &str) -> Option<(Transaction, Block)> {
+        let receipt = self.receipt(hash)?;
+        let block = self.block(receipt.block_index)?;
+        Some((block.transactions.get(receipt.tx_index)?.clone(), block))
+    }
+
+    fn transaction_status(&self, hash: &str) -> TxStatus {
+        if let Some(receipt) = self.receipt(hash) {
+            return TxStatus::Included(receipt);
+        }
+        if self.mempool.contains(hash) {
+            return TxStatus::Pending;
+        }
+        self.tx_failures.get(hash).cloned().unwrap_or(TxStatus::Unknown)
+    }
+
+    fn record_tx_failure(&mut self, hash: String, status: TxStatus) {
+        if self.tx_failures.insert(hash.clone(), status).is_none() {
+            self.tx_failure_order.push_back(hash);
+        }
+        while self.tx_failure_order.len() > MAX_TX_FAILURES {
+            if let Some(oldest) = self.tx_failure_order.pop_front() {
+                self.tx_failures.remove(&oldest);
+            }
+        }
+    }
+
+    fn block_receipts(block: &Block) -> impl Iterator<Item = (String, Receipt)> + '_ {
+        block.transactions.iter().enumerate().map(|(tx_index, tx)| {
+            let receipt = Receipt {
+                block_index: block.index,
+                block_hash: block.hash.clone(),
+                tx_index,
+                fee: Ledger::tx_fee(tx),
+                asset: tx.asset.clone(),
+            };
+            (tx.hash(), receipt)
+        })
+    }
+
+    fn is_known(&self, hash: &str) -> bool {
+        self.block_height(hash).is_some() || self.side_blocks.contains_key(hash)
+    }
+
+    /// Balances a block changed, going from the values in `undo` to the
+    /// ones in `ledger`.
+    fn balance_changes(ledger: &Ledger, undo: &BlockUndo) -> Vec<BalanceChange> {
+        undo.balances
+            .iter()
+            .map(|(address, asset, prev)| {
+                let (prev_balance, prev_nonce) = prev.unwrap_or((0, 0));
+                let (balance, nonce) = ledger.balances
+                    .get(address)
+                    .and_then(|m| m.get(asset))
+                    .copied()
+                    .unwrap_or((0, 0));
+                BalanceChange { address: address.clone(), asset: asset.clone(), balance, nonce, prev_balance, prev_nonce }
+            })
+            .collect()
+    }
+
+    /// Makes `block`, whose changes `undo` records and which the ledger has
+    /// already executed, the new tip. If the storage commit fails the ledger
+    /// is rolled back and nothing else in memory changes.
+    fn push_block(&mut self, block: Block, undo: BlockUndo) -> Result<(), String> {
+        let ledger = &self.ledger;
+        let mut batch = WriteBatch::default();
+        batch.put_block(&block);
+        let mut included = HashSet::new();
+        for (hash, receipt) in Self::block_receipts(&block) {
+            included.insert(hash.clone());
+            batch.put_receipt(hash, receipt);
+        }
+        Self::stage_ledger(&mut batch, ledger, &undo);
+        if self.history == HistoryMode::Archive {
+            Self::stage_balance_history(&mut batch, ledger, block.index, &undo);
+        }
+        let changes = Self::balance_changes(ledger, &undo);
+        batch.put_undo(block.index, undo.clone());
+        if let Some(expired) = block.index.checked_sub(MAX_REORG_DEPTH) {
+            batch.delete_undo(expired);
+        }
+        if let Err(e) = self.storage.commit(batch) {
+            self.ledger.revert(&undo);
+            return Err(e);
+        }
+
+        for hash in &included {
+            self.tx_failures.remove(hash);
+        }
+        self.events.block_applied(AppliedBlock { header: (&block).into(), changes, removed: false });
+        // Announced from the full block, before pruning can drop its body, so the header carries its tx count
+        if let Some(final_block) = block.index.checked_sub(MAX_REORG_DEPTH).and_then(|index| self.block(index)) {
+            self.events.block_finalized(&final_block);
+        }
+        if self.snapshots.is_due(block.index) {
+            if let Err(e) = self.snapshots.write(&block, &self.ledger.snapshot_payload()) {
+                error!("{}", e);
+            }
+        }
+        if let Err(e) = self.prune_bodies() {
+            error!("{}", e);
+        }
+
+        let senders: HashSet<(String, String)> = block.transactions.iter().map(|tx| (tx.from.clone(), tx.asset.clone())).collect();
+        self.tip = Block { transactions: Vec::new(), ..block };
+
+        // Pending txs whose nonce the block used are either in it or can never be included
+        for (sender, asset) in senders {
+            let next_nonce = self.ledger.next_nonce(&sender, &asset);
+            for hash in self.mempool.remove_used(&sender, &asset, next_nonce) {
+                if !included.contains(&hash) {
+                    self.record_tx_failure(hash, TxStatus::Dropped { reason: "Nonce already used on chain".to_string() });
                 }
             }
+        }
+        for hash in self.mempool.expire() {
+            self.record_tx_failure(hash, TxStatus::Dropped { reason: "Expired in the mempool".to_string() });
+        }
+        Ok(())
+    }
 
-            self.distribute_tail_reward();
+    /// Adds the balances and stakes `undo` lists as changed, at their values
+    /// in `ledger`, plus the ledger record.
+    fn stage_ledger(batch: &mut WriteBatch, ledger: &Ledger, undo: &BlockUndo) {
+        for (addr, asset, _) in &undo.balances {
+            batch.put_balance(addr, asset, ledger.balances.get(addr).and_then(|m| m.get(asset)).copied());
+        }
+        for (addr, _) in &undo.staked {
+            batch.put_stake(addr, ledger.staked.get(addr).copied());
+        }
+        batch.put_meta(META_LEDGER, ledger.record());
+    }
 
-            bincode::serialize(&new_block).ok()
-        } else {
-            None
+    /// Validates a transaction against the tip state and adds it to the mempool.
+    /// Nonces up to `NONCE_WINDOW` ahead are queued behind the sender's earlier
+    /// ones. Returns the transaction hash.
+    fn submit_transaction(&mut self, tx: Transaction) -> Result<String, String> {
+        let hash = tx.hash();
+        if self.receipt(&hash).is_some() {
+            return Err("Transaction already included".to_string());
+        }
+        let next_nonce = self.ledger.next_nonce(&tx.from, &tx.asset);
+        if tx.nonce < next_nonce {
+            return Err("Nonce too low".to_string());
+        }
+        if tx.nonce >= next_nonce + mempool::NONCE_WINDOW {
+            return Err(format!("Nonce too far ahead: next is {}", next_nonce));
         }
+        self.ledger.validate_tx_body(&tx, self.next_height())?;
+
+        for (dropped, reason) in self.mempool.insert(tx.clone(), hash.clone(), Ledger::tx_fee(&tx))? {
+            self.record_tx_failure(dropped, TxStatus::Dropped { reason });
+        }
+        self.events.transaction_added(&tx);
+        Ok(hash)
     }
 
-    fn apply_incoming_block(&mut self, block: Block) {
-        if self.validate_block(&block) && block.index as usize == self.blocks.len() {
-            println!("Accepted incoming block {} from network (validator: {})", block.index, block.validator);
-            self.blocks.push(block.clone());
+    /// Pops the tip block and rolls the ledger back to its parent state.
+    fn revert_tip(&mut self) -> Result<Block, String> {
+        let index = self.height();
+        if index == 0 {
+            return Err("Cannot revert the genesis block".to_string());
+        }
+        let undo = self.storage.undo(index)?.ok_or_else(|| format!("No undo record kept for block #{}", index))?;
+        let block = self.storage.block(index)?.ok_or_else(|| format!("Block #{} has no stored body", index))?;
+        let parent = self.storage.header(index - 1)?.ok_or_else(|| format!("Block #{} is missing", index - 1))?;
+        let changes = Self::balance_changes(&self.ledger, &undo);
+        // The current values of everything the block changed, to put back if the commit fails
+        let redo = self.ledger.capture(&undo);
+        self.ledger.revert(&undo);
+
+        let mut batch = WriteBatch::default();
+        batch.delete_block(&block);
+        for tx in &block.transactions {
+            batch.delete_receipt(tx.hash());
+        }
+        batch.delete_undo(index);
+        Self::stage_ledger(&mut batch, &self.ledger, &undo);
+        if self.history == HistoryMode::Archive {
+            for (addr, asset, _) in &undo.balances {
+                batch.delete_balance_history(addr, asset, index);
+            }
+        }
+        if let Err(e) = self.storage.commit(batch) {
+            self.ledger.revert(&redo);
+            return Err(e);
+        }
+        self.tip = parent;
+        self.events.block_applied(AppliedBlock { header: (&block).into(), changes, removed: true });
+        Ok(block)
+    }
 
-            for tx in &block.transactions {
-                if let Err(e) = self.validate_tx(&tx) {
-                    println!("Invalid tx in accepted block: {}", e);
-                    continue;
+    /// Re-executes the block against the ledger and commits it only if every
+    /// transaction, the claimed fees and the state root check out.
+    fn apply_incoming_block(&mut self, block: Block) -> bool {
+        let (index, validator) = (block.index, block.validator.clone());
+        match self.try_apply_block(block) {
+            Ok(()) => {
+                info!("Accepted incoming block {} from network (validator: {})", index, validator);
+                true
+            }
+            Err(BlockRejection::Invalid) => false,
+            Err(BlockRejection::Storage(e)) => {
+                error!("Cannot store block #{}: {}", index, e);
+                false
+            }
+            Err(e) => {
+                warn!("Rejected block #{} from {}: {}", index, validator, e);
+                false
+            }
+        }
+    }
+
+    /// The checks and commit behind `apply_incoming_block`, reporting why a
+    /// block was refused.
+    fn try_apply_block(&mut self, block: Block) -> Result<(), BlockRejection> {
+        if !self.validate_block(&block) || block.index != self.next_height() {
+            return Err(BlockRejection::Invalid);
+        }
+
+        let undo = match self.ledger.execute_block(&block) {
+            Ok(undo) => undo,
+            Err(e) => {
+                if let ExecError::Tx { index, reason } = &e {
+                    let status =
+                        TxStatus::Failed { block_index: block.index, block_hash: block.hash.clone(), reason: reason.clone() };
+                    self.record_tx_failure(block.transactions[*index].hash(), status);
                 }
+                return Err(BlockRejection::Execution(e));
+            }
+        };
 
-                let fee = tx.amount * TX_FEE_BPS / 10000;
-                let (balance, _) = Self::get_balance_mut(&mut self.balances, &tx.from, &tx.asset);
-                *balance -= tx.amount + fee;
+        let state_root = self.ledger.state_root();
+        if state_root != block.state_root {
+            self.ledger.revert(&undo);
+            return Err(BlockRejection::StateRoot { claimed: block.state_root, computed: state_root });
+        }
 
-                let (to_balance, _) = Self::get_balance_mut(&mut self.balances, &tx.to, &tx.asset);
-                *to_balance += tx.amount;
+        self.push_block(block, undo).map_err(BlockRejection::Storage)
+    }
+
+    /// Fork choice: the longer chain wins; at equal height the tip with the
+    /// lower VRF output wins, so every node picks the same block.
+    fn is_better_tip(&self, candidate: &Block) -> bool {
+        let tip = &self.tip;
+        candidate.index > tip.index
+            || (candidate.index == tip.index && Self::vrf_value(candidate) < Self::vrf_value(tip))
+    }
+
+    /// Imports a block that may extend the tip, a side branch, or a branch we
+    /// have not seen the parent of yet.
+    fn import_block(&mut self, block: Block) -> ImportResult {
+        if self.is_known(&block.hash) {
+            return ImportResult::Known;
+        }
+
+        if block.slot > self.current_slot() {
+            debug!("Block #{} is for slot {}, which has not started yet", block.index, block.slot);
+            return ImportResult::Early;
+        }
+
+        if block.prev_hash == self.best_hash() {
+            let (index, validator) = (block.index, block.validator.clone());
+            return match self.try_apply_block(block) {
+                Ok(()) => {
+                    info!("Accepted incoming block {} from network (validator: {})", index, validator);
+                    ImportResult::Applied
+                }
+                Err(BlockRejection::Invalid) => ImportResult::Rejected,
+                // Our failure, not the block's
+                Err(BlockRejection::Storage(e)) => {
+                    error!("Cannot store block #{}: {}", index, e);
+                    ImportResult::Ignored
+                }
+                Err(e) => {
+                    warn!("Rejected block #{} from {}: {}", index, validator, e);
+                    ImportResult::Rejected
+                }
+            };
+        }
+
+        let parent = match self.block_height(&block.prev_hash) {
+            Some(index) => self.header(index),
+            None => self.side_blocks.get(&block.prev_hash).map(|parent| Block { transactions: Vec::new(), ..parent.clone() }),
+        };
+        let Some(parent) = parent else {
+            return ImportResult::Orphan;
+        };
+
+        if block.index != parent.index + 1 {
+            return ImportResult::Rejected;
+        }
+
+        if block.index + MAX_REORG_DEPTH <= self.height() {
+            debug!("Ignoring fork block #{} below the reorg window", block.index);
+            return ImportResult::Ignored;
+        }
+
+        // Checked against the tip's validator keys and stake, which match the
+        // branch's own state unless validators joined or left inside the reorg
+        // window; the branch is fully re-executed before it can win.
+        if !self.validate_header(&block, &parent, &self.ledger) {
+            return ImportResult::Rejected;
+        }
+
+        let competitors = self.side_blocks.values().filter(|b| b.index == block.index).count();
+        if competitors >= MAX_SIDE_BLOCKS_PER_HEIGHT || self.side_blocks.len() >= MAX_SIDE_BLOCKS {
+            debug!("Not keeping side block #{} {}: too many competing blocks", block.index, block.hash);
+            return ImportResult::Ignored;
+        }
+
+        let hash = block.hash.clone();
+        let better = self.is_better_tip(&block);
+        self.side_blocks.insert(hash.clone(), block);
 
-                let (_, nonce) = Self::get_balance_mut(&mut self.balances, &tx.from, &tx.asset);
-                *nonce += 1;
+        let result = if !better {
+            ImportResult::SideChain
+        } else {
+            match self.reorg_to(&hash) {
+                Ok(()) => ImportResult::Reorged,
+                Err(e) => {
+                    warn!("Not switching to block {}: {}", hash, e);
+                    ImportResult::SideChain
+                }
             }
+        };
+        self.prune_side_blocks();
+        result
+    }
 
-            for (asset, total_fee) in block.fees_collected {
-                let validator_share = total_fee / 2;
-                let founder_rake = total_fee - validator_share;
+    /// Rolls back to the common ancestor of `new_tip` and re-applies the
+    /// winning branch. The branch is first executed on a scratch copy of the
+    /// ancestor state, so an invalid branch never touches the stored chain.
+    fn reorg_to(&mut self, new_tip: &str) -> Result<(), String> {
+        let mut branch = Vec::new();
+        let mut cursor = new_tip.to_string();
+        while let Some(block) = self.side_blocks.get(&cursor) {
+            cursor = block.prev_hash.clone();
+            branch.push(block.clone());
+        }
+        branch.reverse();
 
-                let (val_balance, _) = Self::get_balance_mut(&mut self.balances, &block.validator, &asset);
-                *val_balance += validator_share;
+        let ancestor = self.block_height(&cursor).ok_or("branch does not start on the main chain")?;
+        let depth = self.height() - ancestor;
 
-                if asset == "XSX" {
-                    let burn_amount = (founder_rake as f64 * XSX_BURN_RATE) as u64;
-                    let founder_keep = founder_rake - burn_amount;
-                    println!("Burned {} XSX from founder rake", burn_amount);
-                    let (founder_balance, _) = Self::get_balance_mut(&mut self.balances, FOUNDER_ADDRESS, &asset);
-                    *founder_balance += founder_keep;
-                } else {
-                    let (founder_balance, _) = Self::get_balance_mut(&mut self.balances, FOUNDER_ADDRESS, &asset);
-                    *founder_balance += founder_rake;
+        let mut ledger = self.ledger.clone();
+        for index in (ancestor + 1..=self.height()).rev() {
+            let undo = self.storage.undo(index)?
+                .ok_or_else(|| format!("cannot reorg {} blocks deep: no undo record kept for block #{}", depth, index))?;
+            ledger.revert(&undo);
+        }
+        let mut parent = self.header(ancestor).ok_or_else(|| format!("block #{} is missing", ancestor))?;
+        for (position, block) in branch.iter().enumerate() {
+            let valid = self.validate_header(block, &parent, &ledger)
+                && ledger.execute_block(block).is_ok()
+                && ledger.state_root() == block.state_root;
+            if !valid {
+                for invalid in &branch[position..] {
+                    self.side_blocks.remove(&invalid.hash);
+                }
+                return Err(format!("branch block #{} {} is invalid", block.index, block.hash));
+            }
+            parent = Block { transactions: Vec::new(), ..block.clone() };
+        }
+
+        info!(
+            "Reorganizing: rolling back {} block(s) to #{} and applying {} block(s) up to #{}",
+            depth,
+            ancestor,
+            branch.len(),
+            branch.last().map(|b| b.index).unwrap_or(ancestor)
+        );
+
+        let mut detached = Vec::new();
+        let mut applied = 0;
+        let mut failure = None;
+        while self.height() > ancestor {
+            match self.revert_tip() {
+                Ok(block) => detached.push(block),
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        detached.reverse();
+        if failure.is_none() {
+            for block in &branch {
+                if let Err(e) = self.try_apply_block(block.clone()) {
+                    failure = Some(e.to_string());
+                    break;
                 }
+                applied += 1;
             }
+        }
+
+        if let Some(e) = failure {
+            error!("Reorg failed part way ({}) — restoring the previous chain", e);
+            for _ in 0..applied {
+                self.revert_tip()?;
+            }
+            for block in detached {
+                if self.block_height(&block.hash).is_none() {
+                    self.try_apply_block(block).map_err(|e| format!("cannot restore the previous chain: {}", e))?;
+                }
+            }
+            return Err(e);
+        }
+
+        for block in &branch {
+            self.side_blocks.remove(&block.hash);
+        }
+        // Transactions only the old branch had go back to the mempool; ones the new branch included are refused
+        for block in detached {
+            for tx in &block.transactions {
+                if let Err(e) = self.submit_transaction(tx.clone()) {
+                    debug!("Not re-injecting tx {} after reorg: {}", tx.hash(), e);
+                }
+            }
+            self.side_blocks.insert(block.hash.clone(), block);
+        }
+        Ok(())
+    }
+
+    fn prune_side_blocks(&mut self) {
+        let floor = self.height().saturating_sub(MAX_REORG_DEPTH);
+        self.side_blocks.retain(|_, b| b.index > floor);
+    }
+
+    /// Replaces the genesis state with a downloaded snapshot of the state
+    /// after `block`, once the payload is shown to match the block's state
+    /// root. Blocks below it are never fetched.
+    fn install_snapshot(&mut self, block: Block, payload: &[u8]) -> Result<(), String> {
+        if self.height() != 0 {
+            return Err(format!("chain is already at block #{}", self.height()));
+        }
+        let mut ledger = Ledger::from_snapshot(payload)?;
+        if ledger.state_root() != block.state_root {
+            return Err(format!("state does not match the state root of block #{}", block.index));
+        }
+
+        let mut batch = WriteBatch::default();
+        for (addr, asset, _) in self.storage.balances()? {
+            batch.put_balance(&addr, &asset, None);
+        }
+        for (addr, _) in self.storage.stakes()? {
+            batch.put_stake(&addr, None);
+        }
+        batch.append(Self::initial_batch(std::slice::from_ref(&block), &ledger, &[]));
+        batch.put_meta(META_BODY_FLOOR, bincode::serialize(&block.index).expect("Height serializes"));
+        self.storage.commit(batch)?;
+        self.body_floor = block.index;
+        if let Err(e) = self.snapshots.write(&block, payload) {
+            warn!("{}", e);
+        }
 
-            self.distribute_tail_reward();
+        self.side_blocks.clear();
+        // Blocks below the snapshot were never seen here, so there is nothing to announce as final
+        self.events.skip_finalized_to(block.index);
+        self.ledger = ledger;
+        self.tip = Block { transactions: Vec::new(), ..block };
+        // Pending transactions were checked against genesis balances and nonces
+        for tx in std::mem::take(&mut self.mempool).transactions() {
+            if let Err(e) = self.submit_transaction(tx) {
+                debug!("Dropping pool transaction after fast sync: {}", e);
+            }
         }
+        Ok(())
+    }
+
+    /// Saves the pending transactions so they survive a restart.
+    fn save_mempool(&mut self) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        let pool = bincode::serialize(&self.mempool.transactions()).map_err(|e| format!("Cannot encode mempool: {}", e))?;
+        batch.put_meta(META_MEMPOOL, pool);
+        self.storage.commit(batch)
     }
 
-    fn save_to_disk(&self) {
-        let state = self.to_state();
-        if let Ok(encoded) = bincode::serialize(&state) {
-            let _ = fs::write(STATE_FILE, encoded);
-            println!("Chain state saved to {}", STATE_FILE);
+    /// Re-applies a block from an old block log while importing it. A block
+    /// at or below the current height means the node reorganized, so the tip is
+    /// rolled back first.
+    fn replay_block(&mut self, block: Block) -> Result<(), String> {
+        if self.block_height(&block.hash) == Some(block.index) {
+            return Ok(());
         }
+        while self.height() >= block.index {
+            self.revert_tip()
+                .map_err(|e| format!("Block log reorg to #{} cannot be rolled back: {}", block.index, e))?;
+        }
+        let index = block.index;
+        if !self.apply_incoming_block(block) {
+            return Err(format!("Block #{} in the block log does not apply on top of the checkpoint", index));
+        }
+        Ok(())
     }
 }
 
-fn load_chain(node_address: String, node_secret_seed: [u8; 32]) -> MethaloxChain {
-    if let Ok(data) = fs::read(STATE_FILE) {
-        if let Ok(state) = bincode::deserialize::<MethaloxChainState>(&data) {
-            println!("Chain state loaded from {}", STATE_FILE);
-            return MethaloxChain::from_state(state, node_secret_seed, node_address);
+/// Logs a failed storage read and treats the record as missing.
+fn stored<T>(result: Result<Option<T>, String>) -> Option<T> {
+    result.unwrap_or_else(|e| {
+        error!("{}", e);
+        None
+    })
+}
+
+/// Reads the checkpoint file an older node left in the data directory.
+fn read_legacy_state(path: &Path) -> Result<Option<MethaloxChainState>, String> {
+    match legacy::read_checkpoint(path)? {
+        Checkpoint::Valid(payload) => bincode::deserialize(&payload)
+            .map(Some)
+            .map_err(|e| format!("Cannot decode checkpoint {}: {}", path.display(), e)),
+        // Files written before checkpoints carried a checksum are plain bincode
+        Checkpoint::Unverified(data) => bincode::deserialize(&data).map(Some).map_err(|_| {
+            if legacy::is_unversioned_state(&data) {
+                format!(
+                    "{} was written by a node from before chain IDs and genesis files, and that format is not supported; \
+                     move it out of the data directory and resync from genesis",
+                    path.display()
+                )
+            } else {
+                format!("Checkpoint {} is corrupt: checksum mismatch", path.display())
+            }
+        }),
+        Checkpoint::Missing => Ok(None),
+    }
+}
+
+/// Opens the configured storage and the chain in it. A database written by
+/// an older node is migrated to the current schema; an empty one is first
+/// filled from the checkpoint and block log of an older node, if the data
+/// directory has them.
+fn load_chain(config: &NodeConfig, keys: Option<&NodeKeys>, genesis: &GenesisSpec) -> Result<MethaloxChain, String> {
+    let mut storage: Box<dyn ChainStorage> = match config.storage {
+        StorageBackend::Kv => Box::new(KvStorage::open(&config.db_path())?),
+        StorageBackend::Memory => {
+            warn!("Using in-memory storage — the chain is lost when the node stops");
+            Box::new(MemoryStorage::default())
+        }
+    };
+
+    migration::upgrade(storage.as_mut())?;
+
+    let mut legacy_blocks = Vec::new();
+    let mut legacy_pool = Vec::new();
+    if config.storage == StorageBackend::Kv && storage.tip()?.is_none() {
+        let state_path = config.state_path();
+        if let Some(state) = read_legacy_state(&state_path)? {
+            let genesis_hash = MethaloxChain::genesis_block(genesis).hash;
+            let saved_genesis = state.blocks.first().map(|b| b.hash.clone()).unwrap_or_default();
+            if saved_genesis != genesis_hash {
+                return Err(format!(
+                    "{} was created from genesis {} but the configured genesis is {}",
+                    state_path.display(), saved_genesis, genesis_hash
+                ));
+            }
+            info!("Importing {} into {}", state_path.display(), config.db_path().display());
+            legacy_pool = MethaloxChain::import_state(storage.as_mut(), state)?;
         }
+        legacy_blocks = legacy::read_block_log(&config.block_log_path())?;
+    }
+
+    let snapshots = SnapshotStore::open(&config.snapshot_dir(), config.snapshot_interval)?;
+    let mut chain = MethaloxChain::open(storage, config.history, snapshots, keys, genesis)?;
+    if !legacy_blocks.is_empty() {
+        info!("Replaying {} block(s) from {}", legacy_blocks.len(), config.block_log_path().display());
+    }
+    for block in legacy_blocks {
+        chain.replay_block(block)?;
+    }
+    for tx in legacy_pool {
+        if let Err(e) = chain.submit_transaction(tx) {
+            debug!("Dropping saved pool transaction: {}", e);
+        }
+    }
+    info!("Chain loaded at block #{}", chain.height());
+    Ok(chain)
+}
+
+/// The configured genesis spec, or the built-in dev chain if there is none.
+fn load_genesis(config: &NodeConfig) -> Result<GenesisSpec, String> {
+    match &config.genesis {
+        Some(path) => GenesisSpec::load(path),
+        None => GenesisSpec::load_or_dev(&config.genesis_path()),
+    }
+}
+
+/// Keys from the validator keystore, or the public demo seed on the dev chain.
+fn load_keys(config: &NodeConfig, genesis: &GenesisSpec) -> Result<NodeKeys, String> {
+    match config.keystore_path() {
+        Some(path) => {
+            let password = keystore::read_password(config.password_file.as_deref(), false)?;
+            let keys = keystore::load(&path, &password)?;
+            info!("Validator keys loaded from {}", path.display());
+            Ok(keys)
+        }
+        None if genesis.is_dev() => {
+            warn!("No validator keystore configured — using the public demo seed");
+            Ok(NodeKeys::demo(keystore::load_or_create_p2p_identity(&config.data_dir.join(keystore::P2P_KEY_FILE))?))
+        }
+        None => Err(format!(
+            "No validator keystore configured for chain {}; the demo seed is only allowed on the built-in {} genesis. \
+             Create one with `methalox_end_game keys generate`",
+            genesis.chain_id,
+            genesis::DEV_CHAIN_ID
+        )),
+    }
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "MethaloxEvent")]
+struct MethaloxBehaviour {
+    gossipsub: gossipsub::Behaviour<IdentityTransform>,
+    sync: SyncBehaviour,
+    snapshot: snapshot::SnapshotBehaviour,
+}
+
+enum MethaloxEvent {
+    Gossipsub(gossipsub::Event),
+    Sync(SyncEvent),
+    Snapshot(SnapshotEvent),
+}
+
+impl From<gossipsub::Event> for MethaloxEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        MethaloxEvent::Gossipsub(event)
+    }
+}
+
+impl From<SyncEvent> for MethaloxEvent {
+    fn from(event: SyncEvent) -> Self {
+        MethaloxEvent::Sync(event)
+    }
+}
+
+impl From<SnapshotEvent> for MethaloxEvent {
+    fn from(event: SnapshotEvent) -> Self {
+        MethaloxEvent::Snapshot(event)
     }
-    println!("No saved state found — starting fresh genesis");
-    MethaloxChain::new(node_address, node_secret_seed)
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let node_address = "node_001".to_string();
-    let node_secret_seed = [42u8; 32];
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let chain = Arc::new(Mutex::new(load_chain(node_address.clone(), node_secret_seed)));
+/// Transactions are identified by their hash, so the same transaction submitted
+/// through several nodes is delivered once. Blocks keep gossipsub's default id.
+fn gossip_message_id(tx_topic: &TopicHash, message: &gossipsub::Message) -> MessageId {
+    if &message.topic == tx_topic {
+        if let Ok(tx) = bincode::deserialize::<Transaction>(&message.data) {
+            return MessageId::new(tx.hash().as_bytes());
+        }
+    }
+    let source = message.source.map(|peer| peer.to_base58()).unwrap_or_default();
+    MessageId::new(format!("{}{}", source, message.sequence_number.unwrap_or_default()).as_bytes())
+}
 
-    let rpc_chain = chain.clone();
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("keys") {
+        return Ok(keystore::run_command(&args[1..])?);
+    }
+    if args.first().map(String::as_str) == Some("snapshot") {
+        return Ok(snapshot::run_command(&args[1..])?);
+    }
+    if args.first().map(String::as_str) == Some("chain") {
+        return Ok(chain_file::run_command(&args[1..])?);
+    }
 
-    tokio::spawn(async move {
-        let server = ServerBuilder::default().build("0.0.0.0:9933").await.unwrap();
-        let mut module = RpcModule::new(());
-        let _ = module.register_async_method("submit_tx", move |params, _| {
-            let rpc_chain = rpc_chain.clone();
-            async move {
-                let tx_bytes: Vec<u8> = params.one()?;
-                let mut chain_guard = rpc_chain.lock().unwrap();
-                let tx: Transaction = bincode::deserialize(&tx_bytes)
-                    .map_err(|_| jsonrpsee::core::Error::Custom("Invalid transaction format".to_string()))?;
-                if let Err(e) = chain_guard.validate_tx(&tx) {
-                    return Err(jsonrpsee::core::Error::Custom(e));
-                }
-                chain_guard.tx_pool.push(tx);
-                Ok("Transaction submitted successfully".to_string())
-            }
-        });
-        server.start(module).unwrap();
-    });
+    let Some(config) = NodeConfig::from_args(&args)? else {
+        return Ok(());
+    };
+    logger::init(config.log_level);
+    config.prepare_data_dir()?;
 
-    let local_key = identity::Keypair::generate_ed25519();
+    let genesis = load_genesis(&config)?;
+    let genesis_hash = MethaloxChain::genesis_block(&genesis).hash;
+    info!("Chain ID: {} | Genesis hash: {}", genesis.chain_id, genesis_hash);
+
+    let keys = load_keys(&config, &genesis)?;
+    let node_address = keys.address();
+
+    let chain = Arc::new(Mutex::new(load_chain(&config, Some(&keys), &genesis)?));
+
+    let local_key = keys.p2p.clone();
     let local_peer_id = PeerId::from(local_key.public());
-    println!("Local Peer ID: {} | Node Address: {}", local_peer_id, node_address);
+    info!("Local Peer ID: {} | Node Address: {}", local_peer_id, node_address);
+
+    let network_info = Arc::new(Mutex::new(NetworkInfo {
+        peer_id: local_peer_id.to_string(),
+        ..NetworkInfo::default()
+    }));
+
+    let (tx_gossip, mut tx_gossip_rx) = mpsc::unbounded_channel();
+    let module = rpc::build_module(RpcContext {
+        chain: chain.clone(),
+        network: network_info.clone(),
+        tx_gossip,
+    })?;
+
+    let rpc_server = ServerBuilder::default()
+        .build(config.rpc_addr)
+        .await
+        .map_err(|e| format!("Cannot bind RPC server on {}: {}", config.rpc_addr, e))?;
+    // The server shuts down when its handle is dropped, so keep it for the life of main
+    let _rpc_handle = rpc_server.start(module)?;
+    info!("RPC listening on {}", config.rpc_addr);
 
     let transport = tcp::tokio::Transport::new(tcp::Config::default())
         .upgrade(upgrade::Version::V1Lazy)
@@ -541,58 +2396,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .multiplex(yamux::Config::default())
         .boxed();
 
+    // Peers on a different genesis never share a topic, so they never exchange blocks or transactions
+    let topic = IdentTopic::new(format!("methalox-blocks/{}", genesis_hash));
+    let tx_topic = IdentTopic::new(format!("methalox-txs/{}", genesis_hash));
+    let tx_topic_hash = tx_topic.hash();
+
+    let id_topic = tx_topic_hash.clone();
     let gossipsub_config = gossipsub::ConfigBuilder::default()
         .heartbeat_interval(Duration::from_secs(10))
+        .max_transmit_size(MAX_GOSSIP_MESSAGE_SIZE)
+        // Nothing is forwarded until the event loop reports it valid
+        .validate_messages()
+        .message_id_fn(move |message: &gossipsub::Message| gossip_message_id(&id_topic, message))
         .build()?;
 
-    let mut behaviour = gossipsub::Behaviour::<IdentityTransform>::new(
+    let mut gossipsub = gossipsub::Behaviour::<IdentityTransform>::new(
         MessageAuthenticity::Signed(local_key.clone()),
         gossipsub_config,
     )?;
 
-    let topic = IdentTopic::new("methalox-blocks");
-    behaviour.subscribe(&topic)?;
+    gossipsub.subscribe(&topic)?;
+    gossipsub.subscribe(&tx_topic)?;
+
+    let behaviour = MethaloxBehaviour {
+        gossipsub,
+        sync: sync::new_behaviour(),
+        snapshot: snapshot::new_behaviour(),
+    };
+    let mut sync_manager = SyncManager::new(genesis_hash.clone());
+    let mut snapshot_sync = SnapshotSync::new(config.fast_sync.clone(), &chain.lock().unwrap());
 
     let mut swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build();
 
-    // Fixed port for predictable addressing
-    swarm.listen_on("/ip4/0.0.0.0/tcp/4001".parse()?)?;
+    for addr in &config.listen {
+        swarm.listen_on(addr.clone()).map_err(|e| format!("Cannot listen on {}: {}", addr, e))?;
+    }
 
-    // Manual bootstrap dials for multi-node synchronization
-    // Uncomment the lines below and replace with actual Peer IDs from logs to enable connections
-    // swarm.dial("/ip4/129.146.143.135/tcp/4001/p2p/<PEER_ID_FROM_FIRST_NODE>".parse()?)?;
-    // swarm.dial("/ip4/152.70.130.150/tcp/4001/p2p/<PEER_ID_FROM_SECOND_NODE>".parse()?)?;
+    for addr in &config.bootstrap {
+        if let Err(e) = swarm.dial(addr.clone()) {
+            warn!("Failed to dial bootstrap peer {}: {}", addr, e);
+        }
+    }
 
     let chain_clone = chain.clone();
     let topic_clone = topic.clone();
 
-    let mut interval = time::interval(Duration::from_secs(1));
+    let block_interval_ms = config.block_interval_ms.unwrap_or(genesis.slot_duration * 1000);
+    let mut interval = time::interval(Duration::from_millis(block_interval_ms));
+    let mut status_interval = time::interval(Duration::from_secs(30));
 
     let save_chain = chain.clone();
     tokio::spawn(async move {
         signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
-        println!("Shutting down — saving chain state...");
-        save_chain.lock().unwrap().save_to_disk();
+        info!("Shutting down — saving pending transactions...");
+        if let Err(e) = save_chain.lock().unwrap().save_mempool() {
+            error!("{}", e);
+        }
         std::process::exit(0);
     });
 
     loop {
         tokio::select! {
             _ = interval.tick() => {
+                // Producing on top of a stale tip would only fork the network
+                if sync_manager.is_syncing() || snapshot_sync.is_active() {
+                    continue;
+                }
                 let mut chain = chain_clone.lock().unwrap();
                 if let Some(data) = chain.create_block_if_leader() {
-                    let _ = swarm.behaviour_mut().publish(topic_clone.clone(), data);
+                    // Peers still fetch the block by sync, so a failed publish only delays it
+                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_clone.clone(), data) {
+                        warn!("Block #{} not gossiped: {:?}", chain.height(), e);
+                    }
+                }
+            }
+            Some(tx) = tx_gossip_rx.recv() => {
+                // Fails with InsufficientPeers while no peer is connected; the tx is still in the local pool
+                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(tx_topic.clone(), bincode::serialize(&tx)?) {
+                    debug!("Transaction {} not gossiped: {:?}", tx.hash(), e);
+                }
+            }
+            _ = status_interval.tick() => {
+                if snapshot_sync.is_active() {
+                    snapshot_sync.retry(&mut swarm.behaviour_mut().snapshot);
+                    continue;
+                }
+                let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                for peer in peers {
+                    sync_manager.request_status(&mut swarm.behaviour_mut().sync, &peer);
                 }
             }
             event = swarm.select_next_some() => {
                 match event {
                     SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("Listening on {}", address);
+                        let full = format!("{}/p2p/{}", address, local_peer_id);
+                        info!("Listening on {}", full);
+                        network_info.lock().unwrap().listen_addrs.push(full);
+                    }
+                    SwarmEvent::ExpiredListenAddr { address, .. } => {
+                        let full = format!("{}/p2p/{}", address, local_peer_id);
+                        network_info.lock().unwrap().listen_addrs.retain(|a| a != &full);
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } if num_established.get() == 1 => {
+                        network_info.lock().unwrap().connected_peers.push(peer_id.to_string());
+                        if snapshot_sync.is_active() {
+                            snapshot_sync.on_peer_connected(&mut swarm.behaviour_mut().snapshot, peer_id);
+                        } else {
+                            sync_manager.request_status(&mut swarm.behaviour_mut().sync, &peer_id);
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                        let peer = peer_id.to_string();
+                        network_info.lock().unwrap().connected_peers.retain(|p| p != &peer);
+                        snapshot_sync.on_peer_disconnected(&mut swarm.behaviour_mut().snapshot, &peer_id);
+                        let chain = chain_clone.lock().unwrap();
+                        sync_manager.on_peer_disconnected(&mut swarm.behaviour_mut().sync, &chain, &peer_id);
                     }
-                    SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) => {
-                        if let Ok(block) = bincode::deserialize::<Block>(&message.data) {
-                            let mut chain = chain_clone.lock().unwrap();
-                            chain.apply_incoming_block(block);
+                    SwarmEvent::Behaviour(MethaloxEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message })) => {
+                        let acceptance = if message.topic == tx_topic_hash {
+                            match bincode::deserialize::<Transaction>(&message.data) {
+                                // A bad signature is the sender's fault whatever our tip is
+                                Ok(tx) if tx.verify_signature().is_err() => MessageAcceptance::Reject,
+                                Ok(tx) => match chain_clone.lock().unwrap().submit_transaction(tx) {
+                                    Ok(_) => MessageAcceptance::Accept,
+                                    // Duplicates and txs invalid against our tip are not forwarded, but a
+                                    // peer on a different tip may have sent them in good faith
+                                    Err(e) => {
+                                        debug!("Ignoring gossiped transaction from {}: {}", propagation_source, e);
+                                        MessageAcceptance::Ignore
+                                    }
+                                },
+                                Err(_) => MessageAcceptance::Reject,
+                            }
+                        } else {
+                            match bincode::deserialize::<Block>(&message.data) {
+                                // Until the snapshot is installed every block looks like an orphan
+                                Ok(_) if snapshot_sync.is_active() => MessageAcceptance::Ignore,
+                                Ok(block) => {
+                                    let mut chain = chain_clone.lock().unwrap();
+                                    match sync_manager.on_gossip_block(&mut swarm.behaviour_mut().sync, &mut chain, block, propagation_source) {
+                                        ImportResult::Applied | ImportResult::Reorged | ImportResult::SideChain => MessageAcceptance::Accept,
+                                        ImportResult::Rejected => MessageAcceptance::Reject,
+                                        // Duplicates and blocks we cannot judge yet, including ones from a slot our
+                                        // clock has not reached, are dropped without blaming the peer
+                                        ImportResult::Known | ImportResult::Orphan | ImportResult::Ignored | ImportResult::Early => {
+                                            MessageAcceptance::Ignore
+                                        }
+                                    }
+                                }
+                                Err(_) => MessageAcceptance::Reject,
+                            }
+                        };
+                        let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance);
+                    }
+                    SwarmEvent::Behaviour(MethaloxEvent::Sync(event)) => {
+                        let mut chain = chain_clone.lock().unwrap();
+                        if let Some(peer) = sync_manager.on_event(&mut swarm.behaviour_mut().sync, &mut chain, event) {
+                            let _ = swarm.disconnect_peer_id(peer);
+                        }
+                    }
+                    SwarmEvent::Behaviour(MethaloxEvent::Snapshot(event)) => {
+                        let mut chain = chain_clone.lock().unwrap();
+                        if snapshot_sync.on_event(&mut swarm.behaviour_mut().snapshot, &mut chain, event) {
+                            let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                            for peer in peers {
+                                sync_manager.request_status(&mut swarm.behaviour_mut().sync, &peer);
+                            }
                         }
                     }
                     _ => {}
@@ -602,4 +2571,430 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+    use storage::MemoryStorage;
+
+    fn demo_keys() -> NodeKeys {
+        NodeKeys::demo(identity::Keypair::generate_ed25519())
+    }
+
+    /// A dev chain in memory; the demo key holds all the stake, so it leads every slot.
+    fn dev_chain(keys: &NodeKeys) -> MethaloxChain {
+        dev_chain_with_snapshots(keys, Path::new("/nonexistent/methalox-test-snapshots"))
+    }
+
+    fn dev_chain_with_snapshots(keys: &NodeKeys, snapshot_dir: &Path) -> MethaloxChain {
+        let snapshots = SnapshotStore::open(snapshot_dir, None).unwrap();
+        MethaloxChain::open(Box::new(MemoryStorage::default()), HistoryMode::Full, snapshots, Some(keys), &GenesisSpec::dev())
+            .unwrap()
+    }
+
+    fn transfer(keys: &NodeKeys, to: &str, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            from: keys.address(),
+            to: to.to_string(),
+            amount,
+            kind: TransactionKind::Transfer,
+            signature: Vec::new(),
+            timestamp: 0,
+            nonce,
+            commitment: String::new(),
+            blinding_factor: 0,
+            asset: "XSX".to_string(),
+        };
+        tx.signature = keys.account.sign(&tx.signing_bytes()).to_bytes().to_vec();
+        tx
+    }
+
+    fn seal(block: &mut Block, keys: &NodeKeys) {
+        block.hash = MethaloxChain::hash_block(block);
+        block.signature = keys.account.sign(block.hash.as_bytes()).to_bytes().to_vec();
+    }
+
+    /// Produces the child of `parent` at `slot`, given the state after
+    /// `parent`; returns it with the state after it.
+    fn build_block(
+        chain: &MethaloxChain,
+        keys: &NodeKeys,
+        parent: &Block,
+        state: &Ledger,
+        slot: u64,
+        transactions: Vec<Transaction>,
+    ) -> (Block, Ledger) {
+        let transcript = signing_context(VRF_CONTEXT).bytes(&vrf_transcript_bytes(&parent.hash, slot));
+        let (inout, proof, _) = Keypair::from(keys.vrf_secret.clone()).vrf_sign(transcript);
+        let mut fees_collected = HashMap::new();
+        for tx in &transactions {
+            let fee = Ledger::tx_fee(tx);
+            if fee > 0 {
+                *fees_collected.entry(tx.asset.clone()).or_insert(0) += fee;
+            }
+        }
+        let mut block = Block {
+            index: parent.index + 1,
+            slot,
+            timestamp: chain.slot_start(slot),
+            tx_root: tx_root(&transactions),
+            transactions,
+            prev_hash: parent.hash.clone(),
+            hash: String::new(),
+            validator: keys.address(),
+            fees_collected,
+            vrf_proof: proof.to_bytes().to_vec(),
+            vrf_output: inout.to_output().to_bytes().to_vec(),
+            signature: Vec::new(),
+            state_root: String::new(),
+        };
+        let mut ledger = state.clone();
+        if let Err(e) = ledger.execute_block(&block) {
+            panic!("Test block does not execute: {}", e);
+        }
+        block.state_root = ledger.state_root();
+        seal(&mut block, keys);
+        (block, ledger)
+    }
+
+    /// Two blocks at height 1 on genesis, ordered by fork choice: winner first.
+    fn competing_blocks(chain: &MethaloxChain, keys: &NodeKeys) -> ((Block, Ledger), (Block, Ledger)) {
+        let genesis = chain.tip.clone();
+        let first = build_block(chain, keys, &genesis, &chain.ledger, 1, Vec::new());
+        let second = build_block(chain, keys, &genesis, &chain.ledger, 2, Vec::new());
+        if MethaloxChain::vrf_value(&first.0) < MethaloxChain::vrf_value(&second.0) {
+            (first, second)
+        } else {
+            (second, first)
+        }
+    }
+
+    /// The chain's ledger, its stored copy and the tip all agree on `expected`.
+    fn assert_state(chain: &mut MethaloxChain, expected: &Block) {
+        assert_eq!(chain.best_hash(), expected.hash);
+        assert_eq!(chain.ledger.state_root(), expected.state_root);
+        assert_eq!(Ledger::load(chain.storage.as_ref()).unwrap().state_root(), expected.state_root);
+    }
+
+    /// Replaces the body of `block` and re-seals it, leaving the fees and state root as they were.
+    fn with_transactions(mut block: Block, transactions: Vec<Transaction>, keys: &NodeKeys) -> Block {
+        block.tx_root = tx_root(&transactions);
+        block.transactions = transactions;
+        seal(&mut block, keys);
+        block
+    }
+
+    #[test]
+    fn signing_bytes_are_fixed_and_cover_every_field() {
+        let keys = demo_keys();
+        let tx = transfer(&keys, "bob", 5, 1);
+        let mut expected = TX_SIGNING_DOMAIN.to_vec();
+        for field in [tx.from.as_bytes(), b"bob", b"XSX"] {
+            expected.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            expected.extend_from_slice(field);
+        }
+        for value in [5u64, 1, 0] {
+            expected.extend_from_slice(&value.to_be_bytes());
+        }
+        expected.extend_from_slice(&0u32.to_be_bytes());
+        expected.extend_from_slice(&0u64.to_be_bytes());
+        expected.push(0);
+        assert_eq!(tx.signing_bytes(), expected);
+        assert!(tx.verify_signature().is_ok());
+
+        let variants: [fn(&mut Transaction); 8] = [
+            |tx| tx.to = "carol".to_string(),
+            |tx| tx.amount += 1,
+            |tx| tx.nonce += 1,
+            |tx| tx.timestamp += 1,
+            |tx| tx.asset = "USD".to_string(),
+            |tx| tx.commitment = "c".to_string(),
+            |tx| tx.blinding_factor = 1,
+            |tx| tx.kind = TransactionKind::Unstake { amount: 5 },
+        ];
+        for change in variants {
+            let mut changed = tx.clone();
+            change(&mut changed);
+            assert_ne!(changed.signing_bytes(), tx.signing_bytes());
+            assert!(changed.verify_signature().is_err());
+        }
+    }
+
+    #[test]
+    fn repeating_the_last_transaction_changes_the_tx_root() {
+        let keys = demo_keys();
+        let txs: Vec<Transaction> = (1..=3).map(|nonce| transfer(&keys, "bob", 5, nonce)).collect();
+        let mut repeated = txs.clone();
+        repeated.push(txs[2].clone());
+        assert_ne!(tx_root(&txs), tx_root(&repeated));
+    }
+
+    #[test]
+    fn overflowing_amounts_are_rejected() {
+        let keys = demo_keys();
+        let mut ledger = Ledger::from_genesis(&GenesisSpec::dev());
+        let err = ledger.validate_tx(&transfer(&keys, "bob", u64::MAX, 1), 1).unwrap_err();
+        assert_eq!(err, "Amount overflows");
+
+        ledger.balances.entry("bob".to_string()).or_default().insert("XSX".to_string(), (u64::MAX - 1, 0));
+        let err = ledger.validate_tx(&transfer(&keys, "bob", 10, 1), 1).unwrap_err();
+        assert_eq!(err, "Recipient balance overflows");
+        assert!(ledger.validate_tx(&transfer(&keys, "bob", 1, 1), 1).is_ok());
+
+        let mut genesis = GenesisSpec::dev();
+        genesis.allocations.insert("bob".to_string(), [("XSX".to_string(), u64::MAX)].into_iter().collect());
+        assert!(genesis.validate().is_err());
+    }
+
+    /// Every circulating XSX is a balance, stake or unbonding entry, or was burned.
+    fn assert_supply(ledger: &Ledger) {
+        let balances: u64 = ledger.balances.values().filter_map(|assets| assets.get("XSX")).map(|(balance, _)| balance).sum();
+        let staked: u64 = ledger.staked.values().sum();
+        let unbonding: u64 = ledger.unbonding.values().flatten().map(|u| u.amount).sum();
+        assert_eq!(balances + staked + unbonding + ledger.xsx_burned, ledger.xsx_circulating);
+    }
+
+    #[test]
+    fn supply_is_conserved_through_stake_unstake_and_withdraw() {
+        let keys = demo_keys();
+        let chain = dev_chain(&keys);
+        let mut state = chain.ledger.clone();
+        state.unbonding_period = 2;
+        assert_supply(&state);
+
+        let signed = |kind: TransactionKind, amount: u64, nonce: u64| {
+            let mut tx = transfer(&keys, &keys.address(), amount, nonce);
+            tx.kind = kind;
+            tx.signature = keys.account.sign(&tx.signing_bytes()).to_bytes().to_vec();
+            tx
+        };
+        let stake = TransactionKind::Stake {
+            amount: 5_000,
+            vrf_pubkey: keys.vrf_secret.to_public().to_bytes().to_vec(),
+            signing_pubkey: keys.account.verifying_key().to_bytes().to_vec(),
+        };
+        let blocks = vec![
+            vec![transfer(&keys, "bob", 1_000_000, 1), signed(stake, 5_000, 2)],
+            vec![signed(TransactionKind::Unstake { amount: 5_000 }, 5_000, 3)],
+            Vec::new(),
+            vec![signed(TransactionKind::Withdraw, 0, 4)],
+        ];
+
+        let mut parent = chain.tip.clone();
+        for (slot, transactions) in (1..).zip(blocks) {
+            (parent, state) = build_block(&chain, &keys, &parent, &state, slot, transactions);
+            assert_supply(&state);
+        }
+        assert!(state.xsx_burned > 0);
+        assert!(!state.unbonding.contains_key(&keys.address()));
+    }
+
+    #[test]
+    fn blocks_that_do_not_re_execute_are_rejected() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let genesis = chain.tip.clone();
+        let genesis_state = chain.ledger.clone();
+        let tx = transfer(&keys, "bob", 1_000, 1);
+        let (good, _) = build_block(&chain, &keys, &genesis, &genesis_state, 1, vec![tx.clone()]);
+
+        let mut wrong_fees = good.clone();
+        wrong_fees.fees_collected.clear();
+        seal(&mut wrong_fees, &keys);
+        assert!(matches!(chain.try_apply_block(wrong_fees), Err(BlockRejection::Execution(ExecError::Block(_)))));
+
+        let mut wrong_root = good.clone();
+        wrong_root.state_root = genesis.state_root.clone();
+        seal(&mut wrong_root, &keys);
+        assert!(matches!(chain.try_apply_block(wrong_root), Err(BlockRejection::StateRoot { .. })));
+
+        let overdraft = transfer(&keys, "bob", u64::MAX / 2, 1);
+        let overdrawn = with_transactions(good.clone(), vec![overdraft], &keys);
+        assert!(matches!(
+            chain.try_apply_block(overdrawn),
+            Err(BlockRejection::Execution(ExecError::Tx { index: 0, .. }))
+        ));
+
+        let duplicated = with_transactions(good.clone(), vec![tx.clone(), tx], &keys);
+        assert!(matches!(chain.try_apply_block(duplicated), Err(BlockRejection::Invalid)));
+
+        // The body must match the header's transaction root
+        let mut swapped = good.clone();
+        swapped.transactions = vec![transfer(&keys, "bob", 999, 1)];
+        seal(&mut swapped, &keys);
+        assert!(matches!(chain.try_apply_block(swapped), Err(BlockRejection::Invalid)));
+
+        // None of the rejects left a trace in the ledger or the store
+        assert_state(&mut chain, &genesis);
+        assert!(chain.try_apply_block(good.clone()).is_ok());
+        assert_state(&mut chain, &good);
+        assert_eq!(chain.ledger.balances["bob"]["XSX"], (1_000, 0));
+    }
+
+    #[test]
+    fn snapshot_installs_only_with_a_matching_state_root() {
+        let keys = demo_keys();
+        let mut source = dev_chain(&keys);
+        let genesis = source.tip.clone();
+        let genesis_state = source.ledger.clone();
+        let first = transfer(&keys, "bob", 1_000, 1);
+        let (b1, b1_state) = build_block(&source, &keys, &genesis, &genesis_state, 1, vec![first]);
+        let (b2, _) = build_block(&source, &keys, &b1, &b1_state, 2, vec![transfer(&keys, "bob", 2_000, 2)]);
+        source.import_block(b1);
+        source.import_block(b2.clone());
+        let payload = source.ledger.snapshot_payload();
+
+        let dir = std::env::temp_dir().join(format!("methalox-install-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut target = dev_chain_with_snapshots(&keys, &dir);
+        let mut tampered = Ledger::from_snapshot(&payload).unwrap();
+        tampered.balances.get_mut("bob").unwrap().get_mut("XSX").unwrap().0 += 1;
+        assert!(target.install_snapshot(b2.clone(), &tampered.snapshot_payload()).is_err());
+        assert!(target.install_snapshot(b2.clone(), &payload[..payload.len() - 1]).is_err());
+        assert_eq!(target.height(), 0);
+
+        target.install_snapshot(b2.clone(), &payload).unwrap();
+        assert_state(&mut target, &b2);
+        assert_eq!(target.ledger.next_nonce(&keys.address(), "XSX"), 3);
+        assert!(target.block(1).is_none());
+        // The installed snapshot is served on to other peers
+        assert!(target.snapshots.manifest(&b2.hash).is_some());
+        assert!(target.install_snapshot(b2, &payload).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn equal_height_fork_choice_takes_the_lower_vrf_output() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let ((winner, _), (loser, _)) = competing_blocks(&chain, &keys);
+
+        assert!(matches!(chain.import_block(winner.clone()), ImportResult::Applied));
+        assert!(matches!(chain.import_block(loser.clone()), ImportResult::SideChain));
+        assert_eq!(chain.best_hash(), winner.hash);
+
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        assert!(matches!(chain.import_block(loser.clone()), ImportResult::Applied));
+        assert!(matches!(chain.import_block(winner.clone()), ImportResult::Reorged));
+        assert_state(&mut chain, &winner);
+        assert!(matches!(chain.import_block(loser), ImportResult::Known));
+    }
+
+    #[test]
+    fn reorg_round_trip_restores_each_branch() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let genesis = chain.tip.clone();
+        let genesis_state = chain.ledger.clone();
+        let tx = transfer(&keys, "bob", 1_000, 1);
+
+        let (a1, a1_state) = build_block(&chain, &keys, &genesis, &genesis_state, 1, vec![tx.clone()]);
+        assert!(matches!(chain.import_block(a1.clone()), ImportResult::Applied));
+        assert_state(&mut chain, &a1);
+
+        // A longer branch without the transfer takes over and the transfer goes back to the pool
+        let (b1, b1_state) = build_block(&chain, &keys, &genesis, &genesis_state, 2, Vec::new());
+        let (b2, _) = build_block(&chain, &keys, &b1, &b1_state, 3, Vec::new());
+        // Whether b1 alone wins depends on the VRF outputs; b2 wins either way
+        assert!(matches!(chain.import_block(b1), ImportResult::SideChain | ImportResult::Reorged));
+        assert!(matches!(chain.import_block(b2.clone()), ImportResult::Reorged | ImportResult::Applied));
+        assert_state(&mut chain, &b2);
+        assert!(!chain.ledger.balances.contains_key("bob"));
+        assert!(chain.mempool.contains(&tx.hash()));
+        assert!(chain.receipt(&tx.hash()).is_none());
+
+        // Switching back re-applies the transfer exactly once
+        let (a2, a2_state) = build_block(&chain, &keys, &a1, &a1_state, 4, Vec::new());
+        let (a3, _) = build_block(&chain, &keys, &a2, &a2_state, 5, Vec::new());
+        assert!(matches!(chain.import_block(a2), ImportResult::SideChain | ImportResult::Reorged));
+        assert!(matches!(chain.import_block(a3.clone()), ImportResult::Reorged | ImportResult::Applied));
+        assert_state(&mut chain, &a3);
+        assert_eq!(chain.ledger.balances["bob"]["XSX"], (1_000, 0));
+        assert!(!chain.mempool.contains(&tx.hash()));
+        assert_eq!(chain.receipt(&tx.hash()).unwrap().block_hash, a1.hash);
+        assert!(chain.is_known(&b2.hash));
+    }
+
+    #[test]
+    fn invalid_branch_leaves_the_chain_untouched() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let genesis_state = chain.ledger.clone();
+        let ((a1, _), (b1, b1_state)) = competing_blocks(&chain, &keys);
+        assert!(matches!(chain.import_block(a1.clone()), ImportResult::Applied));
+
+        // Signed and linked correctly, but claiming a state the branch does not produce
+        let (mut b2, _) = build_block(&chain, &keys, &b1, &b1_state, 3, Vec::new());
+        b2.state_root = genesis_state.clone().state_root();
+        seal(&mut b2, &keys);
+
+        assert!(matches!(chain.import_block(b1.clone()), ImportResult::SideChain));
+        assert!(matches!(chain.import_block(b2.clone()), ImportResult::SideChain));
+        assert_state(&mut chain, &a1);
+        assert!(!chain.is_known(&b2.hash));
+        assert!(chain.is_known(&b1.hash));
+    }
+
+    #[test]
+    fn blocks_must_carry_the_validator_signature() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let genesis = chain.tip.clone();
+        let (block, _) = build_block(&chain, &keys, &genesis, &chain.ledger, 1, Vec::new());
+
+        let mut unsigned = block.clone();
+        unsigned.signature.clear();
+        assert!(matches!(chain.import_block(unsigned), ImportResult::Rejected));
+
+        let mut forged = block.clone();
+        forged.signature = SigningKey::from_bytes(&[2; 32]).sign(block.hash.as_bytes()).to_bytes().to_vec();
+        assert!(matches!(chain.import_block(forged), ImportResult::Rejected));
+
+        assert_eq!(chain.height(), 0);
+        assert!(matches!(chain.import_block(block), ImportResult::Applied));
+    }
+
+    #[test]
+    fn oversized_blocks_are_rejected_and_responses_are_capped() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let genesis = chain.tip.clone();
+        let (block, _) = build_block(&chain, &keys, &genesis, &chain.ledger, 1, Vec::new());
+
+        let too_many = (1..=MAX_BLOCK_TXS as u64 + 1).map(|nonce| transfer(&keys, "bob", 1, nonce)).collect();
+        assert!(matches!(chain.import_block(with_transactions(block.clone(), too_many, &keys)), ImportResult::Rejected));
+
+        let mut too_big = transfer(&keys, "bob", 1, 1);
+        too_big.commitment = "0".repeat(MAX_BLOCK_TX_BYTES as usize);
+        too_big.signature = keys.account.sign(&too_big.signing_bytes()).to_bytes().to_vec();
+        assert!(matches!(chain.import_block(with_transactions(block.clone(), vec![too_big], &keys)), ImportResult::Rejected));
+
+        assert!(matches!(chain.import_block(block), ImportResult::Applied));
+        assert_eq!(chain.blocks_range(0, 2, u64::MAX).len(), 2);
+        assert_eq!(chain.blocks_range(0, 2, 1).len(), 1);
+    }
+
+    #[test]
+    fn block_slots_must_advance_and_not_run_ahead_of_the_clock() {
+        let keys = demo_keys();
+        let mut chain = dev_chain(&keys);
+        let genesis = chain.tip.clone();
+        let state = chain.ledger.clone();
+
+        let (future, _) = build_block(&chain, &keys, &genesis, &state, chain.current_slot() + 2, Vec::new());
+        assert!(matches!(chain.import_block(future), ImportResult::Early));
+        let (same_slot, _) = build_block(&chain, &keys, &genesis, &state, genesis.slot, Vec::new());
+        assert!(matches!(chain.import_block(same_slot), ImportResult::Rejected));
+
+        let (mut late, _) = build_block(&chain, &keys, &genesis, &state, 1, Vec::new());
+        late.timestamp += 1;
+        seal(&mut late, &keys);
+        assert!(matches!(chain.import_block(late), ImportResult::Rejected));
+
+        let (block, _) = build_block(&chain, &keys, &genesis, &state, 1, Vec::new());
+        assert!(matches!(chain.import_block(block), ImportResult::Applied));
+    }
+}
+
 // יְהֹוָה יִרְאֶה
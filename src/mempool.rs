@@ -0,0 +1,266 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::Transaction;
+
+/// A sender may queue nonces up to this far past its next on-chain nonce,
+/// which also caps how many transactions one sender can have pending per asset.
+pub const NONCE_WINDOW: u64 = 64;
+
+const MAX_POOL_TXS: usize = 10_000;
+
+// A replacement for a pending nonce must raise the fee by at least this much
+const REPLACE_BUMP_PERCENT: u64 = 10;
+
+const TX_TTL: Duration = Duration::from_secs(3600);
+
+struct PoolEntry {
+    tx: Transaction,
+    hash: String,
+    fee: u64,
+    size: u64,
+    added: Instant,
+    // Insertion order, so equal fees are served first come, first served
+    seq: u64,
+}
+
+/// Pending transactions, queued per (sender, asset) in nonce order.
+#[derive(Default)]
+pub struct Mempool {
+    queues: HashMap<(String, String), BTreeMap<u64, PoolEntry>>,
+    by_hash: HashMap<String, (String, String, u64)>,
+    next_seq: u64,
+}
+
+impl Mempool {
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Transaction> {
+        let (sender, asset, nonce) = self.by_hash.get(hash)?;
+        self.queues.get(&(sender.clone(), asset.clone()))?.get(nonce).map(|e| &e.tx)
+    }
+
+    /// All pending transactions, each sender's in nonce order.
+    pub fn transactions(&self) -> Vec<Transaction> {
+        let mut keys: Vec<_> = self.queues.keys().collect();
+        keys.sort();
+        keys.into_iter().flat_map(|key| self.queues[key].values().map(|e| e.tx.clone())).collect()
+    }
+
+    /// Adds a transaction the caller has already validated. On success returns
+    /// the transactions pushed out to make room, with the reason for each.
+    pub fn insert(&mut self, tx: Transaction, hash: String, fee: u64) -> Result<Vec<(String, String)>, String> {
+        if self.contains(&hash) {
+            return Err("Transaction already pending".to_string());
+        }
+        let key = (tx.from.clone(), tx.asset.clone());
+        let mut removed = Vec::new();
+
+        let existing = self.queues.get(&key).and_then(|q| q.get(&tx.nonce)).map(|e| e.fee);
+        if let Some(old_fee) = existing {
+            if fee < old_fee + (old_fee * REPLACE_BUMP_PERCENT / 100).max(1) {
+                return Err(format!(
+                    "Nonce {} already pending; a replacement needs a fee at least {}% higher",
+                    tx.nonce, REPLACE_BUMP_PERCENT
+                ));
+            }
+            if let Some(old) = self.remove_entry(&key, tx.nonce) {
+                removed.push((old.hash, format!("Replaced by {}", hash)));
+            }
+        } else if self.len() >= MAX_POOL_TXS {
+            // Only a queue's last nonce can go without stranding the ones behind it
+            let cheapest = self.queues
+                .iter()
+                .filter_map(|(k, q)| q.last_key_value().map(|(n, e)| (e.fee, Reverse(e.seq), k.clone(), *n)))
+                .min();
+            match cheapest {
+                Some((lowest_fee, _, k, n)) if fee > lowest_fee => {
+                    if let Some(old) = self.remove_entry(&k, n) {
+                        removed.push((old.hash, "Evicted from a full mempool by a higher-fee transaction".to_string()));
+                    }
+                }
+                _ => return Err("Mempool full".to_string()),
+            }
+        }
+
+        self.by_hash.insert(hash.clone(), (key.0.clone(), key.1.clone(), tx.nonce));
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queues
+            .entry(key)
+            .or_default()
+            .insert(tx.nonce, PoolEntry { size: tx.encoded_size(), tx, hash, fee, added: Instant::now(), seq });
+        Ok(removed)
+    }
+
+    /// Removes a sender's transactions with nonces below `next_nonce`, i.e. already used on chain.
+    pub fn remove_used(&mut self, sender: &str, asset: &str, next_nonce: u64) -> Vec<String> {
+        let key = (sender.to_string(), asset.to_string());
+        let used: Vec<u64> = match self.queues.get(&key) {
+            Some(queue) => queue.range(..next_nonce).map(|(n, _)| *n).collect(),
+            None => return Vec::new(),
+        };
+        used.into_iter().filter_map(|n| self.remove_entry(&key, n)).map(|e| e.hash).collect()
+    }
+
+    /// Removes transactions that have waited longer than the TTL.
+    pub fn expire(&mut self) -> Vec<String> {
+        let expired: Vec<((String, String), u64)> = self.queues
+            .iter()
+            .flat_map(|(k, q)| {
+                q.iter().filter(|(_, e)| e.added.elapsed() > TX_TTL).map(move |(n, _)| (k.clone(), *n))
+            })
+            .collect();
+        expired.into_iter().filter_map(|(k, n)| self.remove_entry(&k, n)).map(|e| e.hash).collect()
+    }
+
+    /// Picks up to `max_txs` transactions totalling at most `max_bytes` for a
+    /// block, highest fee first while keeping each sender's nonce order.
+    /// `accept` applies a transaction to the block being built; when it refuses
+    /// one, or the transaction does not fit, the rest of that sender's queue
+    /// waits for a later block.
+    pub fn select(&self, max_txs: usize, max_bytes: u64, mut accept: impl FnMut(&Transaction) -> bool) -> Vec<Transaction> {
+        let mut queues: Vec<_> = self.queues.values().map(|q| q.values().peekable()).collect();
+        let mut heads = BinaryHeap::new();
+        for (i, queue) in queues.iter_mut().enumerate() {
+            if let Some(entry) = queue.peek() {
+                heads.push((entry.fee, Reverse(entry.seq), i));
+            }
+        }
+
+        let mut selected = Vec::new();
+        let mut bytes = 0;
+        while let Some((_, _, i)) = heads.pop() {
+            if selected.len() >= max_txs {
+                break;
+            }
+            let entry = queues[i].next().expect("Queue head was peeked");
+            if bytes + entry.size > max_bytes || !accept(&entry.tx) {
+                continue;
+            }
+            bytes += entry.size;
+            selected.push(entry.tx.clone());
+            if let Some(next) = queues[i].peek() {
+                heads.push((next.fee, Reverse(next.seq), i));
+            }
+        }
+        selected
+    }
+
+    fn remove_entry(&mut self, key: &(String, String), nonce: u64) -> Option<PoolEntry> {
+        let queue = self.queues.get_mut(key)?;
+        let entry = queue.remove(&nonce)?;
+        if queue.is_empty() {
+            self.queues.remove(key);
+        }
+        self.by_hash.remove(&entry.hash);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransactionKind;
+
+    fn tx(from: &str, nonce: u64) -> Transaction {
+        Transaction {
+            from: from.to_string(),
+            to: "carol".to_string(),
+            amount: 1,
+            kind: TransactionKind::Transfer,
+            signature: Vec::new(),
+            timestamp: 0,
+            nonce,
+            commitment: String::new(),
+            blinding_factor: 0,
+            asset: "XSX".to_string(),
+        }
+    }
+
+    fn add(pool: &mut Mempool, from: &str, nonce: u64, fee: u64) -> Result<Vec<(String, String)>, String> {
+        pool.insert(tx(from, nonce), format!("{}-{}-{}", from, nonce, fee), fee)
+    }
+
+    fn picked(selected: &[Transaction]) -> Vec<(String, u64)> {
+        selected.iter().map(|tx| (tx.from.clone(), tx.nonce)).collect()
+    }
+
+    #[test]
+    fn selection_is_by_fee_but_keeps_each_senders_nonce_order() {
+        let mut pool = Mempool::default();
+        add(&mut pool, "alice", 2, 100).unwrap();
+        add(&mut pool, "alice", 1, 1).unwrap();
+        add(&mut pool, "bob", 1, 50).unwrap();
+
+        let all = pool.select(usize::MAX, u64::MAX, |_| true);
+        assert_eq!(picked(&all), [("bob".to_string(), 1), ("alice".to_string(), 1), ("alice".to_string(), 2)]);
+
+        // A refused transaction holds back the sender's later nonces
+        let some = pool.select(usize::MAX, u64::MAX, |tx| !(tx.from == "alice" && tx.nonce == 1));
+        assert_eq!(picked(&some), [("bob".to_string(), 1)]);
+    }
+
+    #[test]
+    fn selection_stops_at_the_block_limits() {
+        let mut pool = Mempool::default();
+        add(&mut pool, "alice", 1, 30).unwrap();
+        add(&mut pool, "alice", 2, 30).unwrap();
+        add(&mut pool, "bob", 1, 20).unwrap();
+        let size = tx("alice", 1).encoded_size();
+
+        let by_count = pool.select(2, u64::MAX, |_| true);
+        assert_eq!(picked(&by_count), [("alice".to_string(), 1), ("alice".to_string(), 2)]);
+
+        let by_bytes = pool.select(usize::MAX, 2 * size + size / 2, |_| true);
+        assert_eq!(by_bytes.len(), 2);
+        assert!(by_bytes.iter().map(Transaction::encoded_size).sum::<u64>() <= 2 * size + size / 2);
+    }
+
+    #[test]
+    fn replacing_a_nonce_needs_a_fee_bump() {
+        let mut pool = Mempool::default();
+        add(&mut pool, "alice", 1, 100).unwrap();
+        assert!(add(&mut pool, "alice", 1, 100).is_err());
+        assert!(add(&mut pool, "alice", 1, 109).is_err());
+
+        let removed = add(&mut pool, "alice", 1, 110).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, "alice-1-100");
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains("alice-1-110") && !pool.contains("alice-1-100"));
+    }
+
+    #[test]
+    fn used_nonces_are_removed() {
+        let mut pool = Mempool::default();
+        for nonce in 1..=3 {
+            add(&mut pool, "alice", nonce, 10).unwrap();
+        }
+        let mut removed = pool.remove_used("alice", "XSX", 3);
+        removed.sort();
+        assert_eq!(removed, ["alice-1-10", "alice-2-10"]);
+        assert_eq!(picked(&pool.transactions()), [("alice".to_string(), 3)]);
+    }
+
+    #[test]
+    fn a_full_pool_only_admits_higher_fees() {
+        let mut pool = Mempool::default();
+        for i in 0..MAX_POOL_TXS {
+            add(&mut pool, &format!("sender{}", i), 1, 5).unwrap();
+        }
+        assert_eq!(add(&mut pool, "late", 1, 5).unwrap_err(), "Mempool full");
+
+        let evicted = add(&mut pool, "late", 1, 6).unwrap();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(pool.len(), MAX_POOL_TXS);
+        assert!(pool.contains("late-1-6"));
+    }
+}
@@ -0,0 +1,124 @@
+use log::info;
+
+use crate::storage::{ChainStorage, WriteBatch, META_SCHEMA_VERSION};
+
+/// Layout of the chain database this node writes. Bump it together with a new
+/// entry in `MIGRATIONS` whenever a table or an encoded record changes shape.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Version history:
+//   0  chain_state.bin + blocks.log, imported by `load_chain` into a new database;
+//      the unversioned chain_state.bin of the first releases cannot be, and is resynced
+//   1  chain.redb; databases written before the version record are version 1 too
+
+type Migration = fn(&dyn ChainStorage) -> Result<WriteBatch, String>;
+
+/// `(from, description, migration)`: upgrades a version `from` database to `from + 1`.
+const MIGRATIONS: &[(u32, &str, Migration)] = &[];
+
+pub fn encode_version(version: u32) -> Vec<u8> {
+    bincode::serialize(&version).expect("Schema version serializes")
+}
+
+/// Schema version of the stored chain; `None` for an empty store, which is
+/// stamped with the current version when the genesis is written.
+fn stored_version(storage: &dyn ChainStorage) -> Result<Option<u32>, String> {
+    match storage.meta(META_SCHEMA_VERSION)? {
+        Some(bytes) => bincode::deserialize(&bytes)
+            .map(Some)
+            .map_err(|e| format!("Unreadable schema version record: {}", e)),
+        None if storage.tip()?.is_none() => Ok(None),
+        // Written before the database carried a version
+        None => Ok(Some(1)),
+    }
+}
+
+/// Brings a stored chain up to `SCHEMA_VERSION`, one version per commit so an
+/// interrupted upgrade resumes where it stopped. A chain written by a newer
+/// node is an error rather than something to overwrite.
+pub fn upgrade(storage: &mut dyn ChainStorage) -> Result<(), String> {
+    let Some(version) = stored_version(storage)? else {
+        return Ok(());
+    };
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Stored chain uses schema version {}, but this node only reads versions up to {}; upgrade the node",
+            version, SCHEMA_VERSION
+        ));
+    }
+    for (from, description, migrate) in MIGRATIONS.iter().filter(|(from, ..)| *from >= version) {
+        info!("Migrating the stored chain from schema version {} to {}: {}", from, from + 1, description);
+        let mut batch = migrate(storage).map_err(|e| format!("Migration to schema version {} failed: {}", from + 1, e))?;
+        batch.put_meta(META_SCHEMA_VERSION, encode_version(from + 1));
+        storage.commit(batch)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::config::HistoryMode;
+    use crate::genesis::GenesisSpec;
+    use crate::snapshot::SnapshotStore;
+    use crate::storage::{KvStorage, META_BODY_FLOOR};
+    use crate::{Ledger, MethaloxChain};
+
+    /// A database as the first version 1 nodes wrote it: the dev genesis,
+    /// without the version record or the body floor.
+    fn unversioned_database(path: &Path) -> KvStorage {
+        let _ = std::fs::remove_file(path);
+        let mut storage = KvStorage::open(path).unwrap();
+        let genesis = GenesisSpec::dev();
+        let mut batch =
+            MethaloxChain::initial_batch(&[MethaloxChain::genesis_block(&genesis)], &Ledger::from_genesis(&genesis), &[]);
+        batch.delete_meta(META_SCHEMA_VERSION);
+        batch.delete_meta(META_BODY_FLOOR);
+        storage.commit(batch).unwrap();
+        storage
+    }
+
+    fn open(storage: KvStorage) -> Result<MethaloxChain, String> {
+        let snapshots = SnapshotStore::open(Path::new("/nonexistent/methalox-test-snapshots"), None).unwrap();
+        MethaloxChain::open(Box::new(storage), HistoryMode::Full, snapshots, None, &GenesisSpec::dev())
+    }
+
+    fn scratch_db(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("methalox-migration-{}-{}.redb", std::process::id(), name))
+    }
+
+    #[test]
+    fn unversioned_database_opens_with_every_body() {
+        let path = scratch_db("unversioned");
+        let mut storage = unversioned_database(&path);
+        assert_eq!(stored_version(&storage).unwrap(), Some(1));
+
+        upgrade(&mut storage).unwrap();
+        assert_eq!(stored_version(&storage).unwrap(), Some(SCHEMA_VERSION));
+
+        // Nothing was ever pruned, so a missing body floor reads as 0
+        let chain = open(storage).unwrap();
+        assert_eq!(chain.height(), 0);
+        assert_eq!(chain.body_floor, 0);
+        drop(chain);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn empty_and_newer_databases() {
+        let path = scratch_db("newer");
+        let _ = std::fs::remove_file(&path);
+        let mut storage = KvStorage::open(&path).unwrap();
+        upgrade(&mut storage).unwrap();
+        assert_eq!(stored_version(&storage).unwrap(), None);
+
+        let mut batch = WriteBatch::default();
+        batch.put_meta(META_SCHEMA_VERSION, encode_version(SCHEMA_VERSION + 1));
+        storage.commit(batch).unwrap();
+        assert!(upgrade(&mut storage).unwrap_err().contains("upgrade the node"));
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
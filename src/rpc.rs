@@ -0,0 +1,354 @@
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Stream, StreamExt};
+use jsonrpsee::core::Error as RpcError;
+use jsonrpsee::server::RpcModule;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+
+use crate::events::{BalanceChange, BlockHeader};
+use crate::{MethaloxChain, Transaction, TransactionKind, Unbonding, SUPPLY_CAP};
+
+/// Live view of the local libp2p node, shared with the RPC server.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct NetworkInfo {
+    pub peer_id: String,
+    /// Dialable addresses including the `/p2p/<peer_id>` suffix, ready for a bootstrap list.
+    pub listen_addrs: Vec<String>,
+    pub connected_peers: Vec<String>,
+}
+
+pub struct RpcContext {
+    pub chain: Arc<Mutex<MethaloxChain>>,
+    pub network: Arc<Mutex<NetworkInfo>>,
+    /// Accepted transactions to publish on the transaction gossip topic.
+    pub tx_gossip: mpsc::UnboundedSender<Transaction>,
+}
+
+/// Language-neutral transaction accepted by `submit_tx_json`. Integers are decimal
+/// strings so they survive JavaScript's 53-bit numbers; keys and the signature are hex.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+struct TransactionJson {
+    from: String,
+    to: String,
+    asset: String,
+    #[serde(with = "decimal")]
+    amount: u64,
+    #[serde(with = "decimal")]
+    nonce: u64,
+    #[serde(with = "decimal")]
+    timestamp: u64,
+    kind: TransactionKindJson,
+    #[serde(default)]
+    commitment: String,
+    #[serde(default, with = "decimal")]
+    blinding_factor: u64,
+    /// Ed25519 signature over the transaction's signing bytes; may be omitted for `get_signing_bytes`.
+    #[serde(default)]
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TransactionKindJson {
+    Transfer,
+    Stake {
+        #[serde(with = "decimal")]
+        amount: u64,
+        vrf_pubkey: String,
+        signing_pubkey: String,
+    },
+    Unstake {
+        #[serde(with = "decimal")]
+        amount: u64,
+    },
+    Withdraw,
+}
+
+impl TryFrom<TransactionJson> for Transaction {
+    type Error = String;
+
+    fn try_from(tx: TransactionJson) -> Result<Self, String> {
+        let kind = match tx.kind {
+            TransactionKindJson::Transfer => TransactionKind::Transfer,
+            TransactionKindJson::Stake { amount, vrf_pubkey, signing_pubkey } => TransactionKind::Stake {
+                amount,
+                vrf_pubkey: hex::decode(&vrf_pubkey).map_err(|_| "Invalid vrf_pubkey (hex)")?,
+                signing_pubkey: hex::decode(&signing_pubkey).map_err(|_| "Invalid signing_pubkey (hex)")?,
+            },
+            TransactionKindJson::Unstake { amount } => TransactionKind::Unstake { amount },
+            TransactionKindJson::Withdraw => TransactionKind::Withdraw,
+        };
+        Ok(Transaction {
+            from: tx.from,
+            to: tx.to,
+            amount: tx.amount,
+            kind,
+            signature: hex::decode(&tx.signature).map_err(|_| "Invalid signature (hex)")?,
+            timestamp: tx.timestamp,
+            nonce: tx.nonce,
+            commitment: tx.commitment,
+            blinding_factor: tx.blinding_factor,
+            asset: tx.asset,
+        })
+    }
+}
+
+/// u64 as a decimal string.
+mod decimal {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(|_| serde::de::Error::custom(format!("invalid decimal integer: {}", raw)))
+    }
+}
+
+#[derive(Serialize)]
+struct TransactionInfo {
+    hash: String,
+    transaction: Transaction,
+    /// `None` while the transaction is still in the mempool.
+    block_index: Option<u64>,
+    block_hash: Option<String>,
+}
+
+/// Pushed to `subscribe_new_heads` subscribers for each block that becomes or stops being the tip.
+#[derive(Serialize)]
+struct HeadUpdate {
+    #[serde(flatten)]
+    header: BlockHeader,
+    removed: bool,
+}
+
+/// Pushed to `subscribe_account` subscribers for each block that touches the account.
+#[derive(Serialize)]
+struct AccountUpdate {
+    block_index: u64,
+    block_hash: String,
+    changes: Vec<BalanceChange>,
+    removed: bool,
+}
+
+#[derive(Serialize)]
+struct ValidatorInfo {
+    address: String,
+    stake: u64,
+    vrf_pubkey: String,
+    signing_pubkey: String,
+}
+
+#[derive(Serialize)]
+struct StakeInfo {
+    address: String,
+    staked: u64,
+    unbonding: Vec<Unbonding>,
+    withdrawable: u64,
+}
+
+#[derive(Serialize)]
+struct SupplyInfo {
+    xsx_circulating: u64,
+    supply_cap: u64,
+    xsx_burned: u64,
+}
+
+/// Registers every JSON-RPC method the node serves.
+pub fn build_module(ctx: RpcContext) -> Result<RpcModule<RpcContext>, RpcError> {
+    let mut module = RpcModule::new(ctx);
+
+    module.register_async_method("submit_tx", |params, ctx| async move {
+        let tx_bytes: Vec<u8> = params.one()?;
+        let tx: Transaction = bincode::deserialize(&tx_bytes)
+            .map_err(|_| RpcError::Custom("Invalid transaction format".to_string()))?;
+        submit(&ctx, tx)
+    })?;
+
+    module.register_async_method("submit_tx_json", |params, ctx| async move {
+        let tx: TransactionJson = params.one()?;
+        submit(&ctx, Transaction::try_from(tx).map_err(RpcError::Custom)?)
+    })?;
+
+    // Lets clients check their signing-bytes encoder against the node's
+    module.register_method("get_signing_bytes", |params, _| {
+        let tx: TransactionJson = params.one()?;
+        let tx = Transaction::try_from(tx).map_err(RpcError::Custom)?;
+        Ok(hex::encode(tx.signing_bytes()))
+    })?;
+
+    module.register_method("get_peer_info", |_, ctx| Ok(ctx.network.lock().unwrap().clone()))?;
+
+    // Returns the (balance, nonce) tuple
+    module.register_method("get_balance", |params, ctx| {
+        let (address, asset): (String, String) = params.parse()?;
+        let chain = ctx.chain.lock().unwrap();
+        Ok(chain.ledger.balances.get(&address).and_then(|m| m.get(&asset)).copied().unwrap_or((0, 0)))
+    })?;
+
+    // Archive nodes only; the (balance, nonce) tuple after the block at `height`
+    module.register_method("get_balance_at", |params, ctx| {
+        let (address, asset, height): (String, String, u64) = params.parse()?;
+        ctx.chain.lock().unwrap().balance_at(&address, &asset, height).map_err(RpcError::Custom)
+    })?;
+
+    module.register_method("get_nonce", |params, ctx| {
+        let (address, asset): (String, String) = params.parse()?;
+        let chain = ctx.chain.lock().unwrap();
+        Ok(chain.ledger.balances.get(&address).and_then(|m| m.get(&asset)).map(|(_, nonce)| *nonce).unwrap_or(0))
+    })?;
+
+    module.register_method("get_block_by_height", |params, ctx| {
+        let height: u64 = params.one()?;
+        let chain = ctx.chain.lock().unwrap();
+        Ok(chain.block(height))
+    })?;
+
+    module.register_method("get_block_by_hash", |params, ctx| {
+        let hash: String = params.one()?;
+        let chain = ctx.chain.lock().unwrap();
+        Ok(chain.block_height(&hash).and_then(|index| chain.block(index)))
+    })?;
+
+    module.register_method("get_latest_block", |_, ctx| {
+        let chain = ctx.chain.lock().unwrap();
+        Ok(chain.block(chain.height()))
+    })?;
+
+    module.register_method("get_transaction", |params, ctx| {
+        let hash: String = params.one()?;
+        let chain = ctx.chain.lock().unwrap();
+        if let Some((tx, block)) = chain.transaction_by_hash(&hash) {
+            return Ok(Some(TransactionInfo {
+                hash,
+                transaction: tx,
+                block_index: Some(block.index),
+                block_hash: Some(block.hash),
+            }));
+        }
+        Ok(chain.mempool
+            .get(&hash)
+            .map(|tx| TransactionInfo { hash: hash.clone(), transaction: tx.clone(), block_index: None, block_hash: None }))
+    })?;
+
+    module.register_method("get_transaction_status", |params, ctx| {
+        let hash: String = params.one()?;
+        Ok(ctx.chain.lock().unwrap().transaction_status(&hash))
+    })?;
+
+    module.register_method("get_validators", |_, ctx| {
+        let chain = ctx.chain.lock().unwrap();
+        let ledger = &chain.ledger;
+        let mut validators: Vec<ValidatorInfo> = ledger.validators
+            .iter()
+            .map(|address| ValidatorInfo {
+                address: address.clone(),
+                stake: ledger.staked.get(address).copied().unwrap_or(0),
+                vrf_pubkey: ledger.vrf_public_keys.get(address).map(|pk| hex::encode(pk.to_bytes())).unwrap_or_default(),
+                signing_pubkey: ledger.signing_keys.get(address).map(|k| hex::encode(k.to_bytes())).unwrap_or_default(),
+            })
+            .collect();
+        validators.sort_by(|a, b| b.stake.cmp(&a.stake).then_with(|| a.address.cmp(&b.address)));
+        Ok(validators)
+    })?;
+
+    module.register_method("get_stake", |params, ctx| {
+        let address: String = params.one()?;
+        let chain = ctx.chain.lock().unwrap();
+        Ok(StakeInfo {
+            staked: chain.ledger.staked.get(&address).copied().unwrap_or(0),
+            unbonding: chain.ledger.unbonding.get(&address).cloned().unwrap_or_default(),
+            withdrawable: chain.ledger.withdrawable(&address, chain.next_height()),
+            address,
+        })
+    })?;
+
+    module.register_method("get_supply", |_, ctx| {
+        let chain = ctx.chain.lock().unwrap();
+        Ok(SupplyInfo {
+            xsx_circulating: chain.ledger.xsx_circulating,
+            supply_cap: SUPPLY_CAP,
+            xsx_burned: chain.ledger.xsx_burned,
+        })
+    })?;
+
+    module.register_method("get_mempool", |_, ctx| {
+        let chain = ctx.chain.lock().unwrap();
+        Ok(chain.mempool.transactions())
+    })?;
+
+    // A reorg first pushes each reverted block with `removed` set, newest first, then the new branch
+    module.register_subscription("subscribe_new_heads", "new_head", "unsubscribe_new_heads", |_, mut sink, ctx| {
+        let blocks = ctx.chain.lock().unwrap().events.subscribe_blocks();
+        let heads = receiver_stream(blocks).map(|applied| HeadUpdate { header: applied.header.clone(), removed: applied.removed });
+        tokio::spawn(async move { sink.pipe_from_stream(Box::pin(heads)).await });
+        Ok(())
+    })?;
+
+    module.register_subscription(
+        "subscribe_new_transactions",
+        "new_transaction",
+        "unsubscribe_new_transactions",
+        |_, mut sink, ctx| {
+            let transactions = receiver_stream(ctx.chain.lock().unwrap().events.subscribe_transactions());
+            tokio::spawn(async move { sink.pipe_from_stream(Box::pin(transactions)).await });
+            Ok(())
+        },
+    )?;
+
+    module.register_subscription("subscribe_account", "account_update", "unsubscribe_account", |params, mut sink, ctx| {
+        let address: String = match params.one() {
+            Ok(address) => address,
+            Err(e) => {
+                let _ = sink.reject(e);
+                return Ok(());
+            }
+        };
+        let blocks = ctx.chain.lock().unwrap().events.subscribe_blocks();
+        let updates = receiver_stream(blocks).filter_map(move |applied| {
+            let changes: Vec<BalanceChange> = applied.changes.iter().filter(|c| c.address == address).cloned().collect();
+            future::ready((!changes.is_empty()).then(|| AccountUpdate {
+                block_index: applied.header.index,
+                block_hash: applied.header.hash.clone(),
+                changes,
+                removed: applied.removed,
+            }))
+        });
+        tokio::spawn(async move { sink.pipe_from_stream(Box::pin(updates)).await });
+        Ok(())
+    })?;
+
+    module.register_subscription("subscribe_finalized", "finalized", "unsubscribe_finalized", |_, mut sink, ctx| {
+        let finalized = receiver_stream::<BlockHeader>(ctx.chain.lock().unwrap().events.subscribe_finalized());
+        tokio::spawn(async move { sink.pipe_from_stream(Box::pin(finalized)).await });
+        Ok(())
+    })?;
+
+    Ok(module)
+}
+
+/// Adds the transaction to the local pool and hands it to the event loop for
+/// gossip. Returns the transaction hash.
+fn submit(ctx: &RpcContext, tx: Transaction) -> Result<String, RpcError> {
+    let hash = ctx.chain.lock().unwrap().submit_transaction(tx.clone()).map_err(RpcError::Custom)?;
+    let _ = ctx.tx_gossip.send(tx);
+    Ok(hash)
+}
+
+/// Stream of broadcast events; a subscriber that lags behind skips what it missed.
+fn receiver_stream<T: Clone + Send + 'static>(receiver: broadcast::Receiver<T>) -> impl Stream<Item = T> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(item) => return Some((item, receiver)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,541 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use libp2p::core::upgrade::ProtocolName;
+use libp2p::request_response::{self, ProtocolSupport, RequestId};
+use libp2p::PeerId;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::{ChainStorage, KvStorage, DB_FILE};
+use crate::sync::BincodeCodec;
+use crate::{stored, Block, Ledger, MethaloxChain};
+
+pub const SNAPSHOT_DIR: &str = "snapshots";
+const SNAPSHOT_PROTOCOL: &[u8] = b"/methalox/snapshot/1";
+
+const MANIFEST_FILE: &str = "manifest.json";
+const SNAPSHOT_VERSION: u32 = 1;
+const CHUNK_SIZE: usize = 1024 * 1024;
+// Largest payload a node writes or downloads. The chunk hashes come from the
+// serving peer, so this bounds what a bad manifest can make us fetch and hold
+// before the state root check rejects it.
+const MAX_SNAPSHOT_SIZE: u64 = 1024 * CHUNK_SIZE as u64;
+
+// Older snapshots are deleted once this many newer ones exist
+const KEEP_SNAPSHOTS: usize = 2;
+
+const SNAPSHOT_USAGE: &str = "\
+Usage: methalox_end_game snapshot export [OPTIONS]
+
+Writes a snapshot of the state at the stored tip. Stop the node first.
+
+Options:
+  --data-dir <DIR>    Data directory (default: data)
+  --output <DIR>      Snapshot directory (default: <data-dir>/snapshots/<height>)";
+
+/// Describes a snapshot: the block whose post-state it holds and the hash of
+/// each chunk of the payload. The block's state root authenticates the payload.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub block: Block,
+    pub size: u64,
+    /// Hex SHA-256 of each chunk, in order.
+    pub chunk_hashes: Vec<String>,
+}
+
+fn chunk_file(index: usize) -> String {
+    format!("chunk-{:05}.bin", index)
+}
+
+/// Writes `payload` as a snapshot directory: `manifest.json` plus one file per
+/// chunk. The files go to a temporary directory that is renamed into place,
+/// so a half-written snapshot is never served.
+pub fn write_snapshot(dir: &Path, block: &Block, payload: &[u8]) -> Result<SnapshotManifest, String> {
+    if payload.len() as u64 > MAX_SNAPSHOT_SIZE {
+        return Err(format!(
+            "State at block #{} is {} bytes, over the {} byte snapshot limit",
+            block.index, payload.len(), MAX_SNAPSHOT_SIZE
+        ));
+    }
+    let tmp = dir.with_extension("tmp");
+    let write = || -> std::io::Result<SnapshotManifest> {
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        let mut chunk_hashes = Vec::new();
+        for (index, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+            fs::write(tmp.join(chunk_file(index)), chunk)?;
+            chunk_hashes.push(hex::encode(Sha256::digest(chunk)));
+        }
+        let manifest = SnapshotManifest {
+            version: SNAPSHOT_VERSION,
+            block: block.clone(),
+            size: payload.len() as u64,
+            chunk_hashes,
+        };
+        fs::write(tmp.join(MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?)?;
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        fs::rename(&tmp, dir)?;
+        Ok(manifest)
+    };
+    write().map_err(|e| format!("Cannot write snapshot {}: {}", dir.display(), e))
+}
+
+fn read_manifest(dir: &Path) -> Result<SnapshotManifest, String> {
+    let path = dir.join(MANIFEST_FILE);
+    let data = fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&data).map_err(|e| format!("Invalid snapshot manifest {}: {}", path.display(), e))
+}
+
+/// Snapshots in `<data-dir>/snapshots`, one directory per height, indexed by
+/// block hash for serving to peers.
+pub struct SnapshotStore {
+    dir: PathBuf,
+    interval: Option<u64>,
+    manifests: HashMap<String, SnapshotManifest>,
+}
+
+impl SnapshotStore {
+    /// Indexes the snapshots already on disk. `interval` is how often a new
+    /// one is taken; `None` only serves existing ones.
+    pub fn open(dir: &Path, interval: Option<u64>) -> Result<Self, String> {
+        let mut manifests = HashMap::new();
+        if dir.exists() {
+            let entries = fs::read_dir(dir).map_err(|e| format!("Cannot read {}: {}", dir.display(), e))?;
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if !path.is_dir() || path.extension().is_some() {
+                    continue;
+                }
+                match read_manifest(&path) {
+                    Ok(manifest) => {
+                        manifests.insert(manifest.block.hash.clone(), manifest);
+                    }
+                    Err(e) => warn!("Skipping snapshot: {}", e),
+                }
+            }
+        }
+        if !manifests.is_empty() {
+            info!("{} state snapshot(s) available in {}", manifests.len(), dir.display());
+        }
+        Ok(Self { dir: dir.to_path_buf(), interval, manifests })
+    }
+
+    pub fn is_due(&self, height: u64) -> bool {
+        self.interval.is_some_and(|n| height > 0 && height.is_multiple_of(n))
+    }
+
+    /// Stores a snapshot taken after `block` and deletes all but the newest few.
+    pub fn write(&mut self, block: &Block, payload: &[u8]) -> Result<(), String> {
+        let manifest = write_snapshot(&self.dir.join(block.index.to_string()), block, payload)?;
+        info!("State snapshot at block #{} written ({} chunk(s))", block.index, manifest.chunk_hashes.len());
+        // A reorg may have replaced the block a snapshot at this height was taken after
+        self.manifests.retain(|_, m| m.block.index != block.index);
+        self.manifests.insert(block.hash.clone(), manifest);
+
+        let mut heights: Vec<u64> = self.manifests.values().map(|m| m.block.index).collect();
+        heights.sort_unstable();
+        for height in heights.iter().rev().skip(KEEP_SNAPSHOTS) {
+            self.manifests.retain(|_, m| m.block.index != *height);
+            if let Err(e) = fs::remove_dir_all(self.dir.join(height.to_string())) {
+                warn!("Cannot delete old snapshot #{}: {}", height, e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn manifest(&self, block_hash: &str) -> Option<&SnapshotManifest> {
+        self.manifests.get(block_hash)
+    }
+
+    pub fn chunk(&self, block_hash: &str, index: u32) -> Result<Option<Vec<u8>>, String> {
+        let Some(manifest) = self.manifests.get(block_hash) else {
+            return Ok(None);
+        };
+        if index as usize >= manifest.chunk_hashes.len() {
+            return Ok(None);
+        }
+        let path = self.dir.join(manifest.block.index.to_string()).join(chunk_file(index as usize));
+        fs::read(&path).map(Some).map_err(|e| format!("Cannot read snapshot chunk {}: {}", path.display(), e))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SnapshotRequest {
+    Manifest { block_hash: String },
+    Chunk { block_hash: String, index: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SnapshotResponse {
+    Manifest(Option<Box<SnapshotManifest>>),
+    Chunk(Option<Vec<u8>>),
+}
+
+#[derive(Clone)]
+pub struct SnapshotProtocol;
+
+impl ProtocolName for SnapshotProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        SNAPSHOT_PROTOCOL
+    }
+}
+
+pub type SnapshotCodec = BincodeCodec<SnapshotProtocol, SnapshotRequest, SnapshotResponse>;
+pub type SnapshotBehaviour = request_response::Behaviour<SnapshotCodec>;
+pub type SnapshotEvent = request_response::Event<SnapshotRequest, SnapshotResponse>;
+
+pub fn new_behaviour() -> SnapshotBehaviour {
+    request_response::Behaviour::new(
+        SnapshotCodec::default(),
+        [(SnapshotProtocol, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+enum FastSyncState {
+    /// Waiting for a peer that has not yet refused.
+    Waiting,
+    Manifest { peer: PeerId, request: RequestId },
+    Chunks { peer: PeerId, request: RequestId, manifest: Box<SnapshotManifest>, payload: Vec<u8> },
+    Done,
+}
+
+/// Serves snapshots to peers and, in fast-sync mode, downloads the snapshot
+/// taken after a trusted block, one chunk at a time from a single peer.
+/// Block sync and production wait until the snapshot is installed.
+pub struct SnapshotSync {
+    trusted_hash: Option<String>,
+    state: FastSyncState,
+    peers: Vec<PeerId>,
+    /// Peers without the snapshot or that served a bad one; cleared on `retry`.
+    refused: HashSet<PeerId>,
+}
+
+impl SnapshotSync {
+    pub fn new(trusted_hash: Option<String>, chain: &MethaloxChain) -> Self {
+        let state = match &trusted_hash {
+            Some(hash) if chain.height() == 0 => {
+                info!("Fast sync: waiting for a peer with the snapshot at block {}", hash);
+                FastSyncState::Waiting
+            }
+            Some(_) => {
+                info!("Chain is already at block #{} — fast sync skipped", chain.height());
+                FastSyncState::Done
+            }
+            None => FastSyncState::Done,
+        };
+        Self { trusted_hash, state, peers: Vec::new(), refused: HashSet::new() }
+    }
+
+    /// True until the trusted snapshot is installed; always false without fast sync.
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, FastSyncState::Done)
+    }
+
+    pub fn on_peer_connected(&mut self, snapshots: &mut SnapshotBehaviour, peer: PeerId) {
+        self.peers.push(peer);
+        self.try_next(snapshots);
+    }
+
+    pub fn on_peer_disconnected(&mut self, snapshots: &mut SnapshotBehaviour, peer: &PeerId) {
+        self.peers.retain(|p| p != peer);
+        if self.active_peer() == Some(*peer) {
+            self.state = FastSyncState::Waiting;
+            self.try_next(snapshots);
+        }
+    }
+
+    /// Gives peers that refused another chance; they may have taken the snapshot since.
+    pub fn retry(&mut self, snapshots: &mut SnapshotBehaviour) {
+        if matches!(self.state, FastSyncState::Waiting) {
+            self.refused.clear();
+            self.try_next(snapshots);
+        }
+    }
+
+    fn active_peer(&self) -> Option<PeerId> {
+        match self.state {
+            FastSyncState::Manifest { peer, .. } | FastSyncState::Chunks { peer, .. } => Some(peer),
+            FastSyncState::Waiting | FastSyncState::Done => None,
+        }
+    }
+
+    fn active_request(&self) -> Option<RequestId> {
+        match self.state {
+            FastSyncState::Manifest { request, .. } | FastSyncState::Chunks { request, .. } => Some(request),
+            FastSyncState::Waiting | FastSyncState::Done => None,
+        }
+    }
+
+    fn try_next(&mut self, snapshots: &mut SnapshotBehaviour) {
+        let (FastSyncState::Waiting, Some(hash)) = (&self.state, &self.trusted_hash) else {
+            return;
+        };
+        let Some(peer) = self.peers.iter().find(|p| !self.refused.contains(p)).copied() else {
+            return;
+        };
+        debug!("Asking {} for the snapshot at block {}", peer, hash);
+        let request = snapshots.send_request(&peer, SnapshotRequest::Manifest { block_hash: hash.clone() });
+        self.state = FastSyncState::Manifest { peer, request };
+    }
+
+    fn refuse(&mut self, peer: PeerId) {
+        self.refused.insert(peer);
+        self.state = FastSyncState::Waiting;
+    }
+
+    /// Handles a snapshot protocol event. Returns true when the snapshot has
+    /// just been installed, so block sync can start.
+    pub fn on_event(&mut self, snapshots: &mut SnapshotBehaviour, chain: &mut MethaloxChain, event: SnapshotEvent) -> bool {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let _ = snapshots.send_response(channel, Self::respond(chain, request));
+                    false
+                }
+                request_response::Message::Response { request_id, response } => {
+                    if self.active_request() != Some(request_id) {
+                        return false;
+                    }
+                    let installed = self.on_response(snapshots, chain, peer, response);
+                    self.try_next(snapshots);
+                    installed
+                }
+            },
+            request_response::Event::OutboundFailure { peer, request_id, error } => {
+                warn!("Snapshot request to {} failed: {}", peer, error);
+                if self.active_request() == Some(request_id) {
+                    self.refuse(peer);
+                    self.try_next(snapshots);
+                }
+                false
+            }
+            request_response::Event::InboundFailure { .. } | request_response::Event::ResponseSent { .. } => false,
+        }
+    }
+
+    fn respond(chain: &MethaloxChain, request: SnapshotRequest) -> SnapshotResponse {
+        match request {
+            SnapshotRequest::Manifest { block_hash } => {
+                SnapshotResponse::Manifest(chain.snapshots.manifest(&block_hash).cloned().map(Box::new))
+            }
+            SnapshotRequest::Chunk { block_hash, index } => {
+                SnapshotResponse::Chunk(stored(chain.snapshots.chunk(&block_hash, index)))
+            }
+        }
+    }
+
+    fn on_response(&mut self, snapshots: &mut SnapshotBehaviour, chain: &mut MethaloxChain, peer: PeerId, response: SnapshotResponse) -> bool {
+        let state = std::mem::replace(&mut self.state, FastSyncState::Waiting);
+        match (state, response) {
+            (FastSyncState::Manifest { .. }, SnapshotResponse::Manifest(Some(manifest))) => {
+                if let Err(e) = self.check_manifest(&manifest) {
+                    warn!("Peer {} sent an unusable snapshot manifest: {}", peer, e);
+                    self.refuse(peer);
+                    return false;
+                }
+                info!(
+                    "Fast sync: downloading the state at block #{} from {} ({} bytes in {} chunk(s))",
+                    manifest.block.index, peer, manifest.size, manifest.chunk_hashes.len()
+                );
+                let payload = Vec::with_capacity(manifest.size as usize);
+                self.request_chunk(snapshots, peer, manifest, payload);
+                false
+            }
+            (FastSyncState::Manifest { .. }, SnapshotResponse::Manifest(None)) => {
+                debug!("Peer {} has no snapshot at the trusted block", peer);
+                self.refuse(peer);
+                false
+            }
+            (FastSyncState::Chunks { manifest, mut payload, .. }, SnapshotResponse::Chunk(Some(chunk))) => {
+                let index = payload.len() / CHUNK_SIZE;
+                // Every chunk but the last is full, so the lengths line up with the manifest's size
+                let expected_len = (manifest.size as usize - payload.len()).min(CHUNK_SIZE);
+                if chunk.len() != expected_len || hex::encode(Sha256::digest(&chunk)) != manifest.chunk_hashes[index] {
+                    warn!("Snapshot chunk {} from {} does not match the manifest", index, peer);
+                    self.refuse(peer);
+                    return false;
+                }
+                payload.extend_from_slice(&chunk);
+                if index + 1 < manifest.chunk_hashes.len() {
+                    self.request_chunk(snapshots, peer, manifest, payload);
+                    return false;
+                }
+
+                let height = manifest.block.index;
+                match chain.install_snapshot(manifest.block, &payload) {
+                    Ok(()) => {
+                        info!("Fast sync: state at block #{} verified and installed — syncing the remaining blocks", height);
+                        self.state = FastSyncState::Done;
+                        true
+                    }
+                    Err(e) => {
+                        warn!("Snapshot from {} rejected: {}", peer, e);
+                        self.refuse(peer);
+                        false
+                    }
+                }
+            }
+            (_, _) => {
+                warn!("Peer {} sent an unexpected snapshot response", peer);
+                self.refuse(peer);
+                false
+            }
+        }
+    }
+
+    /// The manifest must describe the trusted block itself; its chunks are
+    /// checked as they arrive and the assembled state against the block's state root.
+    fn check_manifest(&self, manifest: &SnapshotManifest) -> Result<(), String> {
+        if manifest.version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version {}", manifest.version));
+        }
+        let block = &manifest.block;
+        if Some(&block.hash) != self.trusted_hash.as_ref() || MethaloxChain::hash_block(block) != block.hash {
+            return Err("block does not match the trusted hash".to_string());
+        }
+        if manifest.size == 0 || manifest.size > MAX_SNAPSHOT_SIZE {
+            return Err(format!("size {} is outside 1..={} bytes", manifest.size, MAX_SNAPSHOT_SIZE));
+        }
+        let expected_chunks = (manifest.size as usize).div_ceil(CHUNK_SIZE);
+        if manifest.chunk_hashes.len() != expected_chunks {
+            return Err("chunk count does not match the size".to_string());
+        }
+        Ok(())
+    }
+
+    fn request_chunk(&mut self, snapshots: &mut SnapshotBehaviour, peer: PeerId, manifest: Box<SnapshotManifest>, payload: Vec<u8>) {
+        let index = (payload.len() / CHUNK_SIZE) as u32;
+        let request = snapshots.send_request(&peer, SnapshotRequest::Chunk { block_hash: manifest.block.hash.clone(), index });
+        self.state = FastSyncState::Chunks { peer, request, manifest, payload };
+    }
+}
+
+/// `snapshot export`: writes the state at the stored tip of a stopped node.
+pub fn run_command(args: &[String]) -> Result<(), String> {
+    let Some((command, flags)) = args.split_first() else {
+        return Err(SNAPSHOT_USAGE.to_string());
+    };
+    if command != "export" {
+        return Err(format!("Unknown snapshot command: {}\n\n{}", command, SNAPSHOT_USAGE));
+    }
+
+    let mut data_dir = PathBuf::from(crate::config::DEFAULT_DATA_DIR);
+    let mut output = None;
+    let mut iter = flags.iter();
+    while let Some(flag) = iter.next() {
+        if flag == "-h" || flag == "--help" {
+            println!("{}", SNAPSHOT_USAGE);
+            return Ok(());
+        }
+        let value = iter.next().ok_or_else(|| format!("Missing value for {}\n\n{}", flag, SNAPSHOT_USAGE))?;
+        match flag.as_str() {
+            "--data-dir" => data_dir = PathBuf::from(value),
+            "--output" => output = Some(PathBuf::from(value)),
+            other => return Err(format!("Unknown argument: {}\n\n{}", other, SNAPSHOT_USAGE)),
+        }
+    }
+
+    let db_path = data_dir.join(DB_FILE);
+    if !db_path.exists() {
+        return Err(format!("No chain database at {}", db_path.display()));
+    }
+    let mut storage = KvStorage::open(&db_path)?;
+    crate::migration::upgrade(&mut storage)?;
+    let height = storage.tip()?.ok_or("Chain database is empty")?;
+    let block = storage.block(height)?.ok_or_else(|| format!("Block #{} is missing from the database", height))?;
+    let mut ledger = Ledger::load(&storage)?;
+    if ledger.state_root() != block.state_root {
+        return Err(format!("Stored state does not match the state root of block #{}", height));
+    }
+
+    let dir = output.unwrap_or_else(|| data_dir.join(SNAPSHOT_DIR).join(height.to_string()));
+    let manifest = write_snapshot(&dir, &block, &ledger.snapshot_payload())?;
+    println!("Snapshot of block #{} written to {}", height, dir.display());
+    println!("Block hash: {}", block.hash);
+    println!("Size:       {} bytes in {} chunk(s)", manifest.size, manifest.chunk_hashes.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::GenesisSpec;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("methalox-snapshot-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn genesis_snapshot() -> (Block, Vec<u8>) {
+        let genesis = GenesisSpec::dev();
+        (MethaloxChain::genesis_block(&genesis), Ledger::from_genesis(&genesis).snapshot_payload())
+    }
+
+    fn fast_sync(trusted_hash: &str) -> SnapshotSync {
+        SnapshotSync {
+            trusted_hash: Some(trusted_hash.to_string()),
+            state: FastSyncState::Waiting,
+            peers: Vec::new(),
+            refused: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn store_serves_what_it_wrote() {
+        let dir = scratch_dir("store");
+        let (block, payload) = genesis_snapshot();
+        let mut store = SnapshotStore::open(&dir, Some(10)).unwrap();
+        store.write(&block, &payload).unwrap();
+
+        let reopened = SnapshotStore::open(&dir, None).unwrap();
+        let manifest = reopened.manifest(&block.hash).expect("snapshot indexed");
+        assert_eq!(manifest.size, payload.len() as u64);
+        assert_eq!(reopened.chunk(&block.hash, 0).unwrap(), Some(payload.clone()));
+        assert_eq!(reopened.chunk(&block.hash, 1).unwrap(), None);
+        assert_eq!(reopened.chunk("unknown", 0).unwrap(), None);
+
+        let mut ledger = Ledger::from_snapshot(&payload).unwrap();
+        assert_eq!(ledger.state_root(), block.state_root);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_must_match_the_trusted_block_and_size_limits() {
+        let dir = scratch_dir("manifest");
+        let (block, payload) = genesis_snapshot();
+        let manifest = write_snapshot(&dir, &block, &payload).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let sync = fast_sync(&block.hash);
+        assert!(sync.check_manifest(&manifest).is_ok());
+        assert!(fast_sync("other").check_manifest(&manifest).is_err());
+
+        let mut oversized = manifest.clone();
+        oversized.size = MAX_SNAPSHOT_SIZE + 1;
+        oversized.chunk_hashes = vec![String::new(); oversized.size.div_ceil(CHUNK_SIZE as u64) as usize];
+        assert!(sync.check_manifest(&oversized).is_err());
+
+        let mut extra_chunk = manifest.clone();
+        extra_chunk.chunk_hashes.push(extra_chunk.chunk_hashes[0].clone());
+        assert!(sync.check_manifest(&extra_chunk).is_err());
+
+        let mut empty = manifest.clone();
+        empty.size = 0;
+        empty.chunk_hashes.clear();
+        assert!(sync.check_manifest(&empty).is_err());
+
+        let mut tampered = manifest;
+        tampered.block.state_root = "00".repeat(32);
+        assert!(sync.check_manifest(&tampered).is_err());
+    }
+}
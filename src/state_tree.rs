@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use sha2::{Digest, Sha256};
+
+use crate::merkle_root;
+
+// Leaves are spread over this many buckets by the first bits of their key hash
+const BUCKET_BITS: u32 = 12;
+const BUCKETS: usize = 1 << BUCKET_BITS;
+
+/// Commitment over a keyed set of 32-byte leaves that can be updated one
+/// entry at a time. Each bucket hashes its leaves in key order, and a fixed
+/// binary tree over the buckets gives the root, so changing k entries costs
+/// rehashing k buckets and their paths rather than the whole state.
+#[derive(Clone)]
+pub struct StateTree {
+    buckets: Vec<BTreeMap<[u8; 32], [u8; 32]>>,
+    /// Heap-ordered tree: node `i` hashes nodes `2i` and `2i + 1`, and the
+    /// bucket hashes sit at `BUCKETS..2 * BUCKETS`.
+    nodes: Vec<[u8; 32]>,
+    dirty: BTreeSet<usize>,
+}
+
+impl Default for StateTree {
+    fn default() -> Self {
+        let mut tree = Self {
+            buckets: vec![BTreeMap::new(); BUCKETS],
+            nodes: vec![[0u8; 32]; 2 * BUCKETS],
+            dirty: BTreeSet::new(),
+        };
+        for i in (1..BUCKETS).rev() {
+            tree.nodes[i] = hash_pair(&tree.nodes[2 * i], &tree.nodes[2 * i + 1]);
+        }
+        tree
+    }
+}
+
+impl StateTree {
+    /// Sets or, with `None`, removes the leaf stored under `key`.
+    pub fn set(&mut self, key: [u8; 32], leaf: Option<[u8; 32]>) {
+        let bucket = bucket_of(&key);
+        let changed = match leaf {
+            Some(leaf) => self.buckets[bucket].insert(key, leaf) != Some(leaf),
+            None => self.buckets[bucket].remove(&key).is_some(),
+        };
+        if changed {
+            self.dirty.insert(bucket);
+        }
+    }
+
+    pub fn root(&mut self) -> [u8; 32] {
+        let mut level: BTreeSet<usize> = BTreeSet::new();
+        for bucket in std::mem::take(&mut self.dirty) {
+            self.nodes[BUCKETS + bucket] = merkle_root(self.buckets[bucket].values().copied().collect());
+            level.insert((BUCKETS + bucket) / 2);
+        }
+        while !level.is_empty() {
+            let mut parents = BTreeSet::new();
+            for i in level {
+                self.nodes[i] = hash_pair(&self.nodes[2 * i], &self.nodes[2 * i + 1]);
+                if i > 1 {
+                    parents.insert(i / 2);
+                }
+            }
+            level = parents;
+        }
+        self.nodes[1]
+    }
+}
+
+fn bucket_of(key: &[u8; 32]) -> usize {
+    (u16::from_be_bytes([key[0], key[1]]) >> (16 - BUCKET_BITS)) as usize
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u32) -> [u8; 32] {
+        Sha256::digest(n.to_be_bytes()).into()
+    }
+
+    fn leaf(n: u32) -> [u8; 32] {
+        Sha256::digest([b"leaf".as_slice(), &n.to_be_bytes()].concat()).into()
+    }
+
+    fn built(entries: impl IntoIterator<Item = u32>) -> StateTree {
+        let mut tree = StateTree::default();
+        for n in entries {
+            tree.set(key(n), Some(leaf(n)));
+        }
+        tree
+    }
+
+    #[test]
+    fn root_depends_only_on_the_entries() {
+        let mut forward = built(0..500);
+        let mut backward = built((0..500).rev());
+        assert_eq!(forward.root(), backward.root());
+
+        let mut incremental = built(0..250);
+        incremental.root();
+        for n in 250..500 {
+            incremental.set(key(n), Some(leaf(n)));
+        }
+        assert_eq!(incremental.root(), forward.root());
+    }
+
+    #[test]
+    fn updates_and_removals_match_a_fresh_build() {
+        let mut tree = built(0..300);
+        tree.root();
+        for n in 0..100 {
+            tree.set(key(n), None);
+        }
+        tree.set(key(150), Some(leaf(9999)));
+        let root = tree.root();
+
+        let mut fresh = built(100..300);
+        fresh.set(key(150), Some(leaf(9999)));
+        assert_eq!(root, fresh.root());
+
+        tree.set(key(150), Some(leaf(150)));
+        assert_eq!(tree.root(), built(100..300).root());
+    }
+
+    #[test]
+    fn removing_every_entry_gives_the_empty_root() {
+        let mut tree = built(0..50);
+        tree.root();
+        for n in 0..50 {
+            tree.set(key(n), None);
+        }
+        assert_eq!(tree.root(), StateTree::default().root());
+    }
+}
@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Block, BlockUndo, Receipt, Transaction};
+
+pub const DB_FILE: &str = "chain.redb";
+
+/// Ledger fields other than balances and stakes.
+pub const META_LEDGER: &str = "ledger";
+/// VRF public key of the node that owns the data directory.
+pub const META_NODE_VRF_KEY: &str = "node_vrf_public";
+/// Pending transactions, saved at shutdown.
+pub const META_MEMPOOL: &str = "mempool";
+/// Layout version of the stored chain, see `migration`.
+pub const META_SCHEMA_VERSION: &str = "schema_version";
+/// Lowest height whose body is still stored; bodies below it were pruned or never downloaded.
+pub const META_BODY_FLOOR: &str = "body_floor";
+/// Present while the balance history covers every block since genesis.
+pub const META_ARCHIVE: &str = "archive";
+
+// Column families. Headers and bodies are split so bodies can be dropped
+// while headers stay; state holds the ledger the node rebuilds at startup.
+const HEADERS: TableDefinition<u64, &[u8]> = TableDefinition::new("headers");
+const BODIES: TableDefinition<u64, &[u8]> = TableDefinition::new("bodies");
+const BALANCES: TableDefinition<(&str, &str), (u64, u64)> = TableDefinition::new("state_balances");
+const STAKES: TableDefinition<&str, u64> = TableDefinition::new("state_stakes");
+const META: TableDefinition<&str, &[u8]> = TableDefinition::new("state_meta");
+const UNDO: TableDefinition<u64, &[u8]> = TableDefinition::new("state_undo");
+const BLOCK_HASHES: TableDefinition<&str, u64> = TableDefinition::new("index_block_hashes");
+const RECEIPTS: TableDefinition<&str, &[u8]> = TableDefinition::new("index_receipts");
+// Archive nodes only: each balance as it stood after every block that changed it
+const BALANCE_HISTORY: TableDefinition<HistoryKey, Option<(u64, u64)>> = TableDefinition::new("history_balances");
+
+/// (address, asset, height)
+type HistoryKey = (&'static str, &'static str, u64);
+
+/// (address, asset, (balance, nonce))
+pub type BalanceEntry = (String, String, (u64, u64));
+
+// Boxed: redb's own error type is large
+type DbError = Box<dyn std::error::Error>;
+
+enum Op {
+    PutBlock(Block),
+    DeleteBlock { index: u64, hash: String },
+    PruneBody(u64),
+    PutReceipt(String, Receipt),
+    DeleteReceipt(String),
+    Balance(String, String, Option<(u64, u64)>),
+    Stake(String, Option<u64>),
+    PutUndo(u64, BlockUndo),
+    DeleteUndo(u64),
+    Meta(String, Vec<u8>),
+    DeleteMeta(String),
+    BalanceHistory(String, String, u64, Option<(u64, u64)>),
+    DeleteBalanceHistory(String, String, u64),
+    ClearBalanceHistory,
+}
+
+/// Changes applied all at once by `ChainStorage::commit`.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<Op>,
+}
+
+impl WriteBatch {
+    /// Stores the block's header and body and indexes it by hash.
+    pub fn put_block(&mut self, block: &Block) {
+        self.ops.push(Op::PutBlock(block.clone()));
+    }
+
+    pub fn delete_block(&mut self, block: &Block) {
+        self.ops.push(Op::DeleteBlock { index: block.index, hash: block.hash.clone() });
+    }
+
+    /// Drops a block's transactions but keeps its header and hash index entry.
+    pub fn prune_body(&mut self, index: u64) {
+        self.ops.push(Op::PruneBody(index));
+    }
+
+    pub fn put_receipt(&mut self, tx_hash: String, receipt: Receipt) {
+        self.ops.push(Op::PutReceipt(tx_hash, receipt));
+    }
+
+    pub fn delete_receipt(&mut self, tx_hash: String) {
+        self.ops.push(Op::DeleteReceipt(tx_hash));
+    }
+
+    /// `None` removes the entry.
+    pub fn put_balance(&mut self, address: &str, asset: &str, value: Option<(u64, u64)>) {
+        self.ops.push(Op::Balance(address.to_string(), asset.to_string(), value));
+    }
+
+    /// `None` removes the entry.
+    pub fn put_stake(&mut self, address: &str, value: Option<u64>) {
+        self.ops.push(Op::Stake(address.to_string(), value));
+    }
+
+    pub fn put_undo(&mut self, index: u64, undo: BlockUndo) {
+        self.ops.push(Op::PutUndo(index, undo));
+    }
+
+    pub fn delete_undo(&mut self, index: u64) {
+        self.ops.push(Op::DeleteUndo(index));
+    }
+
+    pub fn put_meta(&mut self, key: &str, value: Vec<u8>) {
+        self.ops.push(Op::Meta(key.to_string(), value));
+    }
+
+    pub fn delete_meta(&mut self, key: &str) {
+        self.ops.push(Op::DeleteMeta(key.to_string()));
+    }
+
+    /// Records a balance as it stood after the block at `height`; `None` if the entry was removed.
+    pub fn put_balance_history(&mut self, address: &str, asset: &str, height: u64, value: Option<(u64, u64)>) {
+        self.ops.push(Op::BalanceHistory(address.to_string(), asset.to_string(), height, value));
+    }
+
+    pub fn delete_balance_history(&mut self, address: &str, asset: &str, height: u64) {
+        self.ops.push(Op::DeleteBalanceHistory(address.to_string(), asset.to_string(), height));
+    }
+
+    pub fn clear_balance_history(&mut self) {
+        self.ops.push(Op::ClearBalanceHistory);
+    }
+
+    /// Queues the operations of `other` after this batch's.
+    pub fn append(&mut self, mut other: WriteBatch) {
+        self.ops.append(&mut other.ops);
+    }
+}
+
+/// Where a chain keeps its blocks, receipts and committed state. The ledger's
+/// working copy lives in memory and is rebuilt from the stored balances,
+/// stakes and ledger record at startup.
+pub trait ChainStorage: Send {
+    /// Height of the highest stored block; `None` for an empty store.
+    fn tip(&self) -> Result<Option<u64>, String>;
+    /// The block at `index` with an empty transaction list.
+    fn header(&self, index: u64) -> Result<Option<Block>, String>;
+    fn block(&self, index: u64) -> Result<Option<Block>, String>;
+    /// Height of a block on the stored chain.
+    fn block_height(&self, hash: &str) -> Result<Option<u64>, String>;
+    fn receipt(&self, tx_hash: &str) -> Result<Option<Receipt>, String>;
+    /// Undo record of the block at `index`, kept for blocks inside the reorg window.
+    fn undo(&self, index: u64) -> Result<Option<BlockUndo>, String>;
+    fn balances(&self) -> Result<Vec<BalanceEntry>, String>;
+    fn stakes(&self) -> Result<Vec<(String, u64)>, String>;
+    fn meta(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+    /// Balance after the block at `height`, from the balance history.
+    fn balance_at(&self, address: &str, asset: &str, height: u64) -> Result<Option<(u64, u64)>, String>;
+    /// Applies the batch atomically: after a crash either all of it or none of it is visible.
+    fn commit(&mut self, batch: WriteBatch) -> Result<(), String>;
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("Stored record serializes")
+}
+
+fn decode<T: DeserializeOwned>(what: &str, bytes: &[u8]) -> Result<T, String> {
+    bincode::deserialize(bytes).map_err(|e| format!("Corrupt {} record in storage: {}", what, e))
+}
+
+/// Splits a block into its header (no transactions) and body.
+fn split_block(block: &Block) -> (Block, &[Transaction]) {
+    let header = Block { transactions: Vec::new(), ..block.clone() };
+    (header, &block.transactions)
+}
+
+/// Keeps everything in maps; nothing survives a restart. For tests and throwaway dev nodes.
+#[derive(Default)]
+pub struct MemoryStorage {
+    headers: BTreeMap<u64, Block>,
+    bodies: HashMap<u64, Vec<Transaction>>,
+    balances: HashMap<(String, String), (u64, u64)>,
+    stakes: HashMap<String, u64>,
+    meta: HashMap<String, Vec<u8>>,
+    undo: HashMap<u64, BlockUndo>,
+    block_hashes: HashMap<String, u64>,
+    receipts: HashMap<String, Receipt>,
+    balance_history: BTreeMap<(String, String, u64), Option<(u64, u64)>>,
+}
+
+impl ChainStorage for MemoryStorage {
+    fn tip(&self) -> Result<Option<u64>, String> {
+        Ok(self.headers.keys().next_back().copied())
+    }
+
+    fn header(&self, index: u64) -> Result<Option<Block>, String> {
+        Ok(self.headers.get(&index).cloned())
+    }
+
+    fn block(&self, index: u64) -> Result<Option<Block>, String> {
+        let (Some(header), Some(body)) = (self.headers.get(&index), self.bodies.get(&index)) else {
+            return Ok(None);
+        };
+        Ok(Some(Block { transactions: body.clone(), ..header.clone() }))
+    }
+
+    fn block_height(&self, hash: &str) -> Result<Option<u64>, String> {
+        Ok(self.block_hashes.get(hash).copied())
+    }
+
+    fn receipt(&self, tx_hash: &str) -> Result<Option<Receipt>, String> {
+        Ok(self.receipts.get(tx_hash).cloned())
+    }
+
+    fn undo(&self, index: u64) -> Result<Option<BlockUndo>, String> {
+        Ok(self.undo.get(&index).cloned())
+    }
+
+    fn balances(&self) -> Result<Vec<BalanceEntry>, String> {
+        Ok(self.balances.iter().map(|((addr, asset), v)| (addr.clone(), asset.clone(), *v)).collect())
+    }
+
+    fn stakes(&self) -> Result<Vec<(String, u64)>, String> {
+        Ok(self.stakes.iter().map(|(addr, v)| (addr.clone(), *v)).collect())
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.meta.get(key).cloned())
+    }
+
+    fn balance_at(&self, address: &str, asset: &str, height: u64) -> Result<Option<(u64, u64)>, String> {
+        let (addr, asset) = (address.to_string(), asset.to_string());
+        Ok(self.balance_history
+            .range((addr.clone(), asset.clone(), 0)..=(addr, asset, height))
+            .next_back()
+            .and_then(|(_, v)| *v))
+    }
+
+    fn commit(&mut self, batch: WriteBatch) -> Result<(), String> {
+        for op in batch.ops {
+            match op {
+                Op::PutBlock(block) => {
+                    let (header, body) = split_block(&block);
+                    self.block_hashes.insert(block.hash.clone(), block.index);
+                    self.bodies.insert(block.index, body.to_vec());
+                    self.headers.insert(block.index, header);
+                }
+                Op::DeleteBlock { index, hash } => {
+                    self.headers.remove(&index);
+                    self.bodies.remove(&index);
+                    self.block_hashes.remove(&hash);
+                }
+                Op::PruneBody(index) => {
+                    self.bodies.remove(&index);
+                }
+                Op::PutReceipt(hash, receipt) => {
+                    self.receipts.insert(hash, receipt);
+                }
+                Op::DeleteReceipt(hash) => {
+                    self.receipts.remove(&hash);
+                }
+                Op::Balance(addr, asset, Some(value)) => {
+                    self.balances.insert((addr, asset), value);
+                }
+                Op::Balance(addr, asset, None) => {
+                    self.balances.remove(&(addr, asset));
+                }
+                Op::Stake(addr, Some(value)) => {
+                    self.stakes.insert(addr, value);
+                }
+                Op::Stake(addr, None) => {
+                    self.stakes.remove(&addr);
+                }
+                Op::PutUndo(index, undo) => {
+                    self.undo.insert(index, undo);
+                }
+                Op::DeleteUndo(index) => {
+                    self.undo.remove(&index);
+                }
+                Op::Meta(key, value) => {
+                    self.meta.insert(key, value);
+                }
+                Op::DeleteMeta(key) => {
+                    self.meta.remove(&key);
+                }
+                Op::BalanceHistory(addr, asset, height, value) => {
+                    self.balance_history.insert((addr, asset, height), value);
+                }
+                Op::DeleteBalanceHistory(addr, asset, height) => {
+                    self.balance_history.remove(&(addr, asset, height));
+                }
+                Op::ClearBalanceHistory => self.balance_history.clear(),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Embedded on-disk key-value store, one table per column family. Every
+/// commit is a single fsynced transaction.
+pub struct KvStorage {
+    db: Database,
+    path: PathBuf,
+}
+
+impl KvStorage {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let db = Database::create(path).map_err(|e| format!("Cannot open database {}: {}", path.display(), e))?;
+        let storage = Self { db, path: path.to_path_buf() };
+        // Read transactions cannot open a table that was never created
+        storage.create_tables().map_err(|e| storage.error(e))?;
+        Ok(storage)
+    }
+
+    fn create_tables(&self) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(HEADERS)?;
+        txn.open_table(BODIES)?;
+        txn.open_table(BALANCES)?;
+        txn.open_table(STAKES)?;
+        txn.open_table(META)?;
+        txn.open_table(UNDO)?;
+        txn.open_table(BLOCK_HASHES)?;
+        txn.open_table(RECEIPTS)?;
+        txn.open_table(BALANCE_HISTORY)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn error(&self, e: impl std::fmt::Display) -> String {
+        format!("Database {}: {}", self.path.display(), e)
+    }
+
+    fn get_by_height(&self, table: TableDefinition<u64, &[u8]>, index: u64) -> Result<Option<Vec<u8>>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(table)?;
+        let value = table.get(index)?.map(|v| v.value().to_vec());
+        Ok(value)
+    }
+
+    fn get_by_key(&self, table: TableDefinition<&str, &[u8]>, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(table)?;
+        let value = table.get(key)?.map(|v| v.value().to_vec());
+        Ok(value)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut headers = txn.open_table(HEADERS)?;
+            let mut bodies = txn.open_table(BODIES)?;
+            let mut balances = txn.open_table(BALANCES)?;
+            let mut stakes = txn.open_table(STAKES)?;
+            let mut meta = txn.open_table(META)?;
+            let mut undo = txn.open_table(UNDO)?;
+            let mut block_hashes = txn.open_table(BLOCK_HASHES)?;
+            let mut receipts = txn.open_table(RECEIPTS)?;
+            let mut balance_history = txn.open_table(BALANCE_HISTORY)?;
+            for op in batch.ops {
+                match op {
+                    Op::PutBlock(block) => {
+                        let (header, body) = split_block(&block);
+                        headers.insert(block.index, encode(&header).as_slice())?;
+                        bodies.insert(block.index, encode(&body).as_slice())?;
+                        block_hashes.insert(block.hash.as_str(), block.index)?;
+                    }
+                    Op::DeleteBlock { index, hash } => {
+                        headers.remove(index)?;
+                        bodies.remove(index)?;
+                        block_hashes.remove(hash.as_str())?;
+                    }
+                    Op::PruneBody(index) => {
+                        bodies.remove(index)?;
+                    }
+                    Op::PutReceipt(hash, receipt) => {
+                        receipts.insert(hash.as_str(), encode(&receipt).as_slice())?;
+                    }
+                    Op::DeleteReceipt(hash) => {
+                        receipts.remove(hash.as_str())?;
+                    }
+                    Op::Balance(addr, asset, Some(value)) => {
+                        balances.insert((addr.as_str(), asset.as_str()), value)?;
+                    }
+                    Op::Balance(addr, asset, None) => {
+                        balances.remove((addr.as_str(), asset.as_str()))?;
+                    }
+                    Op::Stake(addr, Some(value)) => {
+                        stakes.insert(addr.as_str(), value)?;
+                    }
+                    Op::Stake(addr, None) => {
+                        stakes.remove(addr.as_str())?;
+                    }
+                    Op::PutUndo(index, record) => {
+                        undo.insert(index, encode(&record).as_slice())?;
+                    }
+                    Op::DeleteUndo(index) => {
+                        undo.remove(index)?;
+                    }
+                    Op::Meta(key, value) => {
+                        meta.insert(key.as_str(), value.as_slice())?;
+                    }
+                    Op::DeleteMeta(key) => {
+                        meta.remove(key.as_str())?;
+                    }
+                    Op::BalanceHistory(addr, asset, height, value) => {
+                        balance_history.insert((addr.as_str(), asset.as_str(), height), value)?;
+                    }
+                    Op::DeleteBalanceHistory(addr, asset, height) => {
+                        balance_history.remove((addr.as_str(), asset.as_str(), height))?;
+                    }
+                    Op::ClearBalanceHistory => balance_history.retain(|_, _| false)?,
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+impl ChainStorage for KvStorage {
+    fn tip(&self) -> Result<Option<u64>, String> {
+        let read = || -> Result<Option<u64>, DbError> {
+            let txn = self.db.begin_read()?;
+            let table = txn.open_table(HEADERS)?;
+            let last = table.last()?.map(|(k, _)| k.value());
+            Ok(last)
+        };
+        read().map_err(|e| self.error(e))
+    }
+
+    fn header(&self, index: u64) -> Result<Option<Block>, String> {
+        match self.get_by_height(HEADERS, index).map_err(|e| self.error(e))? {
+            Some(bytes) => decode("header", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn block(&self, index: u64) -> Result<Option<Block>, String> {
+        let Some(header) = self.header(index)? else {
+            return Ok(None);
+        };
+        match self.get_by_height(BODIES, index).map_err(|e| self.error(e))? {
+            Some(bytes) => Ok(Some(Block { transactions: decode("body", &bytes)?, ..header })),
+            None => Ok(None),
+        }
+    }
+
+    fn block_height(&self, hash: &str) -> Result<Option<u64>, String> {
+        let read = || -> Result<Option<u64>, DbError> {
+            let txn = self.db.begin_read()?;
+            let table = txn.open_table(BLOCK_HASHES)?;
+            let index = table.get(hash)?.map(|v| v.value());
+            Ok(index)
+        };
+        read().map_err(|e| self.error(e))
+    }
+
+    fn receipt(&self, tx_hash: &str) -> Result<Option<Receipt>, String> {
+        match self.get_by_key(RECEIPTS, tx_hash).map_err(|e| self.error(e))? {
+            Some(bytes) => decode("receipt", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn undo(&self, index: u64) -> Result<Option<BlockUndo>, String> {
+        match self.get_by_height(UNDO, index).map_err(|e| self.error(e))? {
+            Some(bytes) => decode("undo", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn balances(&self) -> Result<Vec<BalanceEntry>, String> {
+        let read = || -> Result<Vec<BalanceEntry>, DbError> {
+            let txn = self.db.begin_read()?;
+            let table = txn.open_table(BALANCES)?;
+            let mut entries = Vec::new();
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                let (addr, asset) = key.value();
+                entries.push((addr.to_string(), asset.to_string(), value.value()));
+            }
+            Ok(entries)
+        };
+        read().map_err(|e| self.error(e))
+    }
+
+    fn stakes(&self) -> Result<Vec<(String, u64)>, String> {
+        let read = || -> Result<Vec<(String, u64)>, DbError> {
+            let txn = self.db.begin_read()?;
+            let table = txn.open_table(STAKES)?;
+            let mut entries = Vec::new();
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                entries.push((key.value().to_string(), value.value()));
+            }
+            Ok(entries)
+        };
+        read().map_err(|e| self.error(e))
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.get_by_key(META, key).map_err(|e| self.error(e))
+    }
+
+    fn balance_at(&self, address: &str, asset: &str, height: u64) -> Result<Option<(u64, u64)>, String> {
+        let read = || -> Result<Option<(u64, u64)>, DbError> {
+            let txn = self.db.begin_read()?;
+            let table = txn.open_table(BALANCE_HISTORY)?;
+            let last = table.range((address, asset, 0)..=(address, asset, height))?.next_back().transpose()?;
+            Ok(last.and_then(|(_, v)| v.value()))
+        };
+        read().map_err(|e| self.error(e))
+    }
+
+    fn commit(&mut self, batch: WriteBatch) -> Result<(), String> {
+        self.write(batch).map_err(|e| self.error(e))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::GenesisSpec;
+    use crate::{MethaloxChain, TransactionKind};
+
+    fn scratch_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("methalox-storage-{}-{}.redb", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn block(index: u64, tag: &str, transactions: Vec<Transaction>) -> Block {
+        let genesis = MethaloxChain::genesis_block(&GenesisSpec::dev());
+        Block { index, hash: format!("{}-{}", tag, index), transactions, ..genesis }
+    }
+
+    fn tx(nonce: u64) -> Transaction {
+        Transaction {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 3,
+            kind: TransactionKind::Transfer,
+            signature: Vec::new(),
+            timestamp: 0,
+            nonce,
+            commitment: String::new(),
+            blinding_factor: 0,
+            asset: "XSX".to_string(),
+        }
+    }
+
+    fn receipt(block: &Block) -> Receipt {
+        Receipt { block_index: block.index, block_hash: block.hash.clone(), tx_index: 0, fee: 1, asset: "XSX".to_string() }
+    }
+
+    /// Batches covering every kind of write, including deletes of what earlier batches wrote.
+    fn batches() -> Vec<WriteBatch> {
+        let mut first = WriteBatch::default();
+        for b in [block(0, "main", Vec::new()), block(1, "main", vec![tx(1)]), block(2, "main", vec![tx(2), tx(3)])] {
+            first.put_block(&b);
+        }
+        first.put_receipt("tx1".to_string(), receipt(&block(1, "main", Vec::new())));
+        first.put_receipt("tx2".to_string(), receipt(&block(2, "main", Vec::new())));
+        first.put_balance("alice", "XSX", Some((10, 2)));
+        first.put_balance("bob", "XSX", Some((5, 0)));
+        first.put_stake("alice", Some(7));
+        first.put_undo(1, BlockUndo { xsx_circulating: 1, ..BlockUndo::default() });
+        first.put_undo(2, BlockUndo { xsx_burned: 2, ..BlockUndo::default() });
+        first.put_meta("note", b"kept".to_vec());
+        first.put_meta("gone", b"dropped".to_vec());
+        first.put_balance_history("alice", "XSX", 1, Some((13, 1)));
+        first.put_balance_history("alice", "XSX", 2, Some((10, 2)));
+        first.put_balance_history("bob", "XSX", 2, None);
+
+        // A one-block rollback and a pruned body
+        let mut second = WriteBatch::default();
+        second.delete_block(&block(2, "main", Vec::new()));
+        second.delete_receipt("tx2".to_string());
+        second.delete_undo(2);
+        second.put_balance("bob", "XSX", None);
+        second.put_stake("alice", None);
+        second.delete_meta("gone");
+        second.delete_balance_history("alice", "XSX", 2);
+        second.prune_body(1);
+
+        let mut third = WriteBatch::default();
+        third.put_block(&block(2, "side", vec![tx(2)]));
+        third.put_balance_history("alice", "XSX", 2, Some((9, 2)));
+        let mut fourth = WriteBatch::default();
+        fourth.clear_balance_history();
+        vec![first, second, third, fourth]
+    }
+
+    /// Everything readable through `ChainStorage`, encoded so two backends can be compared.
+    fn view(storage: &dyn ChainStorage) -> Vec<Vec<u8>> {
+        let mut balances = storage.balances().unwrap();
+        balances.sort();
+        let mut stakes = storage.stakes().unwrap();
+        stakes.sort();
+        let mut out = vec![encode(&storage.tip().unwrap()), encode(&balances), encode(&stakes)];
+        for index in 0..4 {
+            out.push(encode(&storage.header(index).unwrap()));
+            out.push(encode(&storage.block(index).unwrap()));
+            out.push(encode(&storage.undo(index).unwrap()));
+            for address in ["alice", "bob"] {
+                out.push(encode(&storage.balance_at(address, "XSX", index).unwrap()));
+            }
+        }
+        for hash in ["main-1", "main-2", "side-2"] {
+            out.push(encode(&storage.block_height(hash).unwrap()));
+        }
+        for hash in ["tx1", "tx2"] {
+            out.push(encode(&storage.receipt(hash).unwrap()));
+        }
+        for key in ["note", "gone"] {
+            out.push(encode(&storage.meta(key).unwrap()));
+        }
+        out
+    }
+
+    #[test]
+    fn kv_and_memory_storage_agree() {
+        let path = scratch_db("parity");
+        let mut kv = KvStorage::open(&path).unwrap();
+        let mut memory = MemoryStorage::default();
+        assert_eq!(view(&kv), view(&memory));
+        for (kv_batch, memory_batch) in batches().into_iter().zip(batches()) {
+            kv.commit(kv_batch).unwrap();
+            memory.commit(memory_batch).unwrap();
+            assert_eq!(view(&kv), view(&memory));
+        }
+        assert_eq!(memory.tip().unwrap(), Some(2));
+        assert!(memory.block(1).unwrap().is_none() && memory.header(1).unwrap().is_some());
+        drop(kv);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn kv_storage_survives_a_reopen() {
+        let path = scratch_db("reopen");
+        let mut kv = KvStorage::open(&path).unwrap();
+        for batch in batches().into_iter().take(3) {
+            kv.commit(batch).unwrap();
+        }
+        let before = view(&kv);
+        drop(kv);
+        assert_eq!(view(&KvStorage::open(&path).unwrap()), before);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::{self, ProtocolSupport, RequestId};
+use libp2p::PeerId;
+use log::{debug, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{Block, ImportResult, MethaloxChain, MAX_REORG_DEPTH};
+
+const SYNC_PROTOCOL: &[u8] = b"/methalox/sync/1";
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+pub const MAX_BLOCKS_PER_REQUEST: u64 = 128;
+// A blocks response stops before this many bytes, well under MAX_MESSAGE_SIZE
+const MAX_RESPONSE_BYTES: u64 = 16 * 1024 * 1024;
+const MAX_ORPHANS: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SyncRequest {
+    Status,
+    BlocksByHeight { start: u64, count: u64 },
+    BlockByHash { hash: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SyncResponse {
+    Status { genesis_hash: String, height: u64, best_hash: String },
+    Blocks(Vec<Block>),
+}
+
+#[derive(Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        SYNC_PROTOCOL
+    }
+}
+
+/// Length-prefixed bincode framing for a request-response protocol.
+pub struct BincodeCodec<P, Req, Res>(PhantomData<(P, Req, Res)>);
+
+impl<P, Req, Res> Default for BincodeCodec<P, Req, Res> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<P, Req, Res> Clone for BincodeCodec<P, Req, Res> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[async_trait]
+impl<P, Req, Res> request_response::Codec for BincodeCodec<P, Req, Res>
+where
+    P: ProtocolName + Clone + Send + Sync + 'static,
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Res: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Protocol = P;
+    type Request = Req;
+    type Response = Res;
+
+    async fn read_request<T>(&mut self, _: &P, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(&read_length_prefixed(io, MAX_MESSAGE_SIZE).await?)
+    }
+
+    async fn read_response<T>(&mut self, _: &P, io: &mut T) -> io::Result<Res>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(&read_length_prefixed(io, MAX_MESSAGE_SIZE).await?)
+    }
+
+    async fn write_request<T>(&mut self, _: &P, io: &mut T, req: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&req)?).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &P, io: &mut T, res: Res) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&res)?).await?;
+        io.close().await
+    }
+}
+
+pub type SyncCodec = BincodeCodec<SyncProtocol, SyncRequest, SyncResponse>;
+pub type SyncBehaviour = request_response::Behaviour<SyncCodec>;
+pub type SyncEvent = request_response::Event<SyncRequest, SyncResponse>;
+
+pub fn new_behaviour() -> SyncBehaviour {
+    request_response::Behaviour::new(
+        SyncCodec::default(),
+        [(SyncProtocol, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+enum SyncState {
+    Idle,
+    Downloading { peer: PeerId, request: RequestId, target: u64 },
+    FetchingParent { peer: PeerId, request: RequestId },
+}
+
+/// Tracks peer heights, buffers gossip blocks that arrive ahead of the local
+/// tip or on an unknown branch, and drives block range downloads and parent
+/// lookups until the gap is filled.
+pub struct SyncManager {
+    genesis_hash: String,
+    state: SyncState,
+    peer_heights: HashMap<PeerId, u64>,
+    orphans: BTreeMap<(u64, String), Block>,
+}
+
+impl SyncManager {
+    pub fn new(genesis_hash: String) -> Self {
+        Self {
+            genesis_hash,
+            state: SyncState::Idle,
+            peer_heights: HashMap::new(),
+            orphans: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        !matches!(self.state, SyncState::Idle)
+    }
+
+    pub fn request_status(&mut self, sync: &mut SyncBehaviour, peer: &PeerId) {
+        sync.send_request(peer, SyncRequest::Status);
+    }
+
+    pub fn on_peer_disconnected(&mut self, sync: &mut SyncBehaviour, chain: &MethaloxChain, peer: &PeerId) {
+        self.peer_heights.remove(peer);
+        if self.active_peer() == Some(*peer) {
+            self.state = SyncState::Idle;
+        }
+        self.maybe_start(sync, chain);
+    }
+
+    /// Imports a gossiped block, following up on orphans, and returns the
+    /// outcome so the caller can score the message.
+    pub fn on_gossip_block(
+        &mut self,
+        sync: &mut SyncBehaviour,
+        chain: &mut MethaloxChain,
+        block: Block,
+        source: PeerId,
+    ) -> ImportResult {
+        let index = block.index;
+        let result = chain.import_block(block.clone());
+        match result {
+            ImportResult::Applied | ImportResult::Reorged | ImportResult::SideChain => self.drain_orphans(chain),
+            ImportResult::Known | ImportResult::Ignored | ImportResult::Rejected | ImportResult::Early => {}
+            ImportResult::Orphan => {
+                if index + MAX_REORG_DEPTH <= chain.height() {
+                    return result;
+                }
+                let height = self.peer_heights.entry(source).or_insert(0);
+                *height = (*height).max(index);
+                let parent = block.prev_hash.clone();
+                self.buffer_orphan(block);
+                if index <= chain.next_height() {
+                    // Parent is on a branch we have not seen; ask for it directly
+                    self.fetch_parent(sync, source, parent);
+                } else {
+                    self.maybe_start(sync, chain);
+                }
+            }
+        }
+        result
+    }
+
+    fn active_peer(&self) -> Option<PeerId> {
+        match self.state {
+            SyncState::Idle => None,
+            SyncState::Downloading { peer, .. } | SyncState::FetchingParent { peer, .. } => Some(peer),
+        }
+    }
+
+    fn active_request(&self) -> Option<RequestId> {
+        match self.state {
+            SyncState::Idle => None,
+            SyncState::Downloading { request, .. } | SyncState::FetchingParent { request, .. } => Some(request),
+        }
+    }
+
+    fn fetch_parent(&mut self, sync: &mut SyncBehaviour, peer: PeerId, hash: String) {
+        if self.is_syncing() {
+            return;
+        }
+        debug!("Fetching fork parent {} from {}", hash, peer);
+        let request = sync.send_request(&peer, SyncRequest::BlockByHash { hash });
+        self.state = SyncState::FetchingParent { peer, request };
+    }
+
+    /// Handles a sync protocol event. Returns a peer that should be
+    /// disconnected because it is on a different genesis.
+    pub fn on_event(&mut self, sync: &mut SyncBehaviour, chain: &mut MethaloxChain, event: SyncEvent) -> Option<PeerId> {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let response = Self::respond(chain, request);
+                    let _ = sync.send_response(channel, response);
+                    None
+                }
+                request_response::Message::Response { request_id, response } => {
+                    self.on_response(sync, chain, peer, request_id, response)
+                }
+            },
+            request_response::Event::OutboundFailure { peer, request_id, error } => {
+                warn!("Sync request to {} failed: {}", peer, error);
+                if self.active_request() == Some(request_id) {
+                    self.state = SyncState::Idle;
+                }
+                self.peer_heights.remove(&peer);
+                self.maybe_start(sync, chain);
+                None
+            }
+            request_response::Event::InboundFailure { .. } | request_response::Event::ResponseSent { .. } => None,
+        }
+    }
+
+    fn respond(chain: &MethaloxChain, request: SyncRequest) -> SyncResponse {
+        match request {
+            SyncRequest::Status => SyncResponse::Status {
+                genesis_hash: chain.genesis_hash(),
+                height: chain.height(),
+                best_hash: chain.best_hash(),
+            },
+            SyncRequest::BlocksByHeight { start, count } => {
+                SyncResponse::Blocks(chain.blocks_range(start, count.min(MAX_BLOCKS_PER_REQUEST), MAX_RESPONSE_BYTES))
+            }
+            SyncRequest::BlockByHash { hash } => {
+                SyncResponse::Blocks(chain.block_by_hash(&hash).into_iter().collect())
+            }
+        }
+    }
+
+    fn on_response(
+        &mut self,
+        sync: &mut SyncBehaviour,
+        chain: &mut MethaloxChain,
+        peer: PeerId,
+        request_id: RequestId,
+        response: SyncResponse,
+    ) -> Option<PeerId> {
+        match response {
+            SyncResponse::Status { genesis_hash, height, .. } => {
+                if genesis_hash != self.genesis_hash {
+                    warn!("Peer {} is on genesis {} — disconnecting", peer, genesis_hash);
+                    self.peer_heights.remove(&peer);
+                    return Some(peer);
+                }
+                self.peer_heights.insert(peer, height);
+                self.maybe_start(sync, chain);
+            }
+            SyncResponse::Blocks(blocks) => {
+                let mut applied = 0;
+                let mut missing_parent = None;
+                for block in blocks {
+                    match chain.import_block(block.clone()) {
+                        ImportResult::Applied | ImportResult::Reorged | ImportResult::SideChain => applied += 1,
+                        ImportResult::Known | ImportResult::Ignored => {}
+                        ImportResult::Orphan => {
+                            missing_parent = Some(block.prev_hash.clone());
+                            self.buffer_orphan(block);
+                            break;
+                        }
+                        // The rest of the range builds on this block
+                        ImportResult::Rejected | ImportResult::Early => break,
+                    }
+                }
+                self.drain_orphans(chain);
+
+                if self.active_request() == Some(request_id) {
+                    let target = match self.state {
+                        SyncState::Downloading { target, .. } => Some(target),
+                        _ => None,
+                    };
+                    self.state = SyncState::Idle;
+                    if let Some(parent) = missing_parent {
+                        // Peer is on a fork we diverged from; walk back towards the common ancestor
+                        self.fetch_parent(sync, peer, parent);
+                        return None;
+                    }
+                    if applied == 0 {
+                        // Peer served nothing usable; stop trusting its advertised height
+                        warn!("Sync from {} made no progress", peer);
+                        self.peer_heights.remove(&peer);
+                    } else if target.is_some_and(|t| chain.height() >= t) {
+                        info!("Sync complete at block #{}", chain.height());
+                    }
+                }
+                self.maybe_start(sync, chain);
+            }
+        }
+        None
+    }
+
+    fn maybe_start(&mut self, sync: &mut SyncBehaviour, chain: &MethaloxChain) {
+        if self.is_syncing() {
+            return;
+        }
+
+        let local = chain.height();
+        let Some((peer, target)) = self.peer_heights
+            .iter()
+            .filter(|(_, h)| **h > local)
+            .max_by_key(|(_, h)| **h)
+            .map(|(p, h)| (*p, *h))
+        else {
+            return;
+        };
+
+        let start = chain.next_height();
+        let count = (target - local).min(MAX_BLOCKS_PER_REQUEST);
+        info!("Syncing blocks #{}..#{} from {} (peer height {})", start, start + count - 1, peer, target);
+        let request = sync.send_request(&peer, SyncRequest::BlocksByHeight { start, count });
+        self.state = SyncState::Downloading { peer, request, target };
+    }
+
+    fn buffer_orphan(&mut self, block: Block) {
+        self.orphans.insert((block.index, block.hash.clone()), block);
+        while self.orphans.len() > MAX_ORPHANS {
+            // Keep the blocks closest to the tip; they are the ones we can use first
+            self.orphans.pop_last();
+        }
+    }
+
+    /// Imports every buffered block whose parent is now known, repeating
+    /// until no more connect. Blocks below the reorg window are dropped.
+    fn drain_orphans(&mut self, chain: &mut MethaloxChain) {
+        loop {
+            let floor = chain.height().saturating_sub(MAX_REORG_DEPTH);
+            while let Some(entry) = self.orphans.first_entry() {
+                if entry.key().0 > floor {
+                    break;
+                }
+                entry.remove();
+            }
+
+            let ready: Vec<(u64, String)> = self.orphans
+                .iter()
+                .filter(|(_, b)| chain.is_known(&b.prev_hash))
+                .map(|(k, _)| k.clone())
+                .collect();
+            if ready.is_empty() {
+                return;
+            }
+
+            for key in ready {
+                let block = self.orphans.remove(&key).expect("orphan key collected above");
+                chain.import_block(block);
+            }
+        }
+    }
+}